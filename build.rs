@@ -6,6 +6,10 @@ fn main() {
         yespower_build();
         x16s_build();
         x11_build();
+        if !cfg!(windows) {
+            // avoid multiple definition error by GCC linker (sha3)
+            println!("cargo:rustc-link-arg=-Wl,-z,muldefs");
+        }
     } else {
         eprintln!("skip compile");
     }
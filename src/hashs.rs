@@ -69,21 +69,12 @@ pub fn get_x16s_hash<T: AsRef<[u8]>>(input: T) -> Vec<u8> {
     }
 }
 
-#[cfg(windows)]
+// note: GCC linker allows multiple definitions (sha3) by build.rs
 #[link(name = "x11", kind = "static")]
 extern "C" {
     fn x11_hash(input: *const c_char, output: *mut c_char);
 }
 
-/// avoid multiple definition error by GCC linker (sha3)
-#[cfg(not(windows))]
-#[link(name = "x11", kind = "static")]
-#[allow(unused_attributes)]
-#[link_args = "-z muldefs"]
-extern "C" {
-    fn x11_hash(input: *const c_char, output: *mut c_char);
-}
-
 /// x11 hash
 ///
 /// input 80 bytes vec or slice and output 32 bytes vec
@@ -1,4 +1,3 @@
-#![feature(link_args)]
 extern crate num_cpus;
 
 // proof of capacity
@@ -0,0 +1,130 @@
+use crate::capacity::nonces_in_free_space;
+use crate::error::PocError;
+use crate::plotfile::{parse_journal, Address, PlotFile};
+use crate::{PocParams, PocVersion};
+use fs2::FileExt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// hand out nonce ranges of an address not used by plots or other plotters
+///
+/// reservations are "start-end" lines of a file shared by all plotters,
+/// updated under an exclusive file lock so parallel processes never collide
+#[derive(Clone, Debug, PartialEq)]
+pub struct RangeAllocator {
+    pub addr: Address,
+    /// plot directories inspected for used ranges
+    pub dirs: Vec<PathBuf>,
+    pub reservations: PathBuf,
+    pub version: PocVersion,
+    pub params: PocParams,
+}
+
+impl RangeAllocator {
+    /// allocator of mainnet v1 plots
+    pub fn new<P: AsRef<Path>>(addr: &[u8], dirs: &[P], reservations: &Path) -> Self {
+        RangeAllocator {
+            addr: addr.to_vec(),
+            dirs: dirs.iter().map(|dir| dir.as_ref().to_path_buf()).collect(),
+            reservations: reservations.to_path_buf(),
+            version: PocVersion::V1,
+            params: PocParams::MAINNET,
+        }
+    }
+
+    /// reserve the lowest free range of the nonces
+    pub fn allocate(&self, nonces: usize) -> Result<Range<usize>, PocError> {
+        if nonces == 0 {
+            return Err(PocError::Invalid("allocate zero nonces".to_owned()));
+        }
+        let mut fs = self.lock()?;
+        let mut used = self.plotted()?;
+        used.extend(read_reservations(&mut fs)?);
+        used.sort_by_key(|range| range.start);
+
+        // first gap fit the nonces
+        let mut start = 0;
+        for range in used.iter() {
+            if start + nonces <= range.start {
+                break;
+            }
+            start = start.max(range.end);
+        }
+        let range = start..start + nonces;
+        fs.seek(SeekFrom::End(0))?;
+        writeln!(fs, "{}-{}", range.start, range.end)?;
+        fs.sync_data()?;
+        Ok(range)
+    }
+
+    /// reserve a range fit in the free space of the plot directory
+    pub fn allocate_for_dir(&self, dir: &Path, reserve: u64) -> Result<Range<usize>, PocError> {
+        let nonces = nonces_in_free_space(&self.params, dir, reserve)?;
+        if nonces == 0 {
+            return Err(PocError::NoSpace {
+                needed: self.params.nonce_bytes() as u64,
+                available: 0,
+            });
+        }
+        self.allocate(nonces)
+    }
+
+    /// remove the reservation, ex. plotting was abandoned
+    pub fn release(&self, range: Range<usize>) -> Result<(), PocError> {
+        let mut fs = self.lock()?;
+        let left = read_reservations(&mut fs)?
+            .into_iter()
+            .filter(|reserved| *reserved != range)
+            .map(|reserved| format!("{}-{}\n", reserved.start, reserved.end))
+            .collect::<String>();
+        fs.set_len(0)?;
+        fs.seek(SeekFrom::Start(0))?;
+        fs.write_all(left.as_bytes())?;
+        fs.sync_data()?;
+        Ok(())
+    }
+
+    /// reserved ranges ordered by start
+    pub fn reserved(&self) -> Result<Vec<Range<usize>>, PocError> {
+        let mut ranges = read_reservations(&mut self.lock()?)?;
+        ranges.sort_by_key(|range| range.start);
+        Ok(ranges)
+    }
+
+    /// open reservation file locked until dropped
+    fn lock(&self) -> Result<File, PocError> {
+        let fs = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.reservations)?;
+        fs.lock_exclusive()?;
+        Ok(fs)
+    }
+
+    /// ranges of plot files of the address and version in all directories
+    fn plotted(&self) -> Result<Vec<Range<usize>>, PocError> {
+        let mut ranges = vec![];
+        for dir in self.dirs.iter() {
+            for plot in PlotFile::scan_dir(dir, &self.params)?.plots {
+                if plot.addr == self.addr && plot.version == self.version {
+                    ranges.push(plot.start..plot.end);
+                }
+            }
+        }
+        Ok(ranges)
+    }
+}
+
+fn read_reservations(fs: &mut File) -> Result<Vec<Range<usize>>, PocError> {
+    let mut data = String::new();
+    fs.seek(SeekFrom::Start(0))?;
+    fs.read_to_string(&mut data)?;
+    Ok(parse_journal(&data)
+        .into_iter()
+        .map(|(start, end)| start..end)
+        .collect())
+}
@@ -0,0 +1,28 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// shareable handle to stop plotting, converting and seeking
+///
+/// clones share one flag, checked between chunks
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    /// request all jobs holding this token to stop
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// clear the flag to reuse the token
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
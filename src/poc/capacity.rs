@@ -0,0 +1,58 @@
+use crate::error::PocError;
+use crate::PocParams;
+use fs2::FileExt;
+use std::fs::File;
+use std::ops::Range;
+use std::path::Path;
+
+/// bytes of a plot file of the nonces, same for both layouts
+pub fn plot_bytes(params: &PocParams, nonces: usize) -> u64 {
+    (nonces as u64).saturating_mul(params.nonce_bytes() as u64)
+}
+
+/// nonces fit in the bytes
+pub fn nonces_in_bytes(params: &PocParams, bytes: u64) -> usize {
+    (bytes / params.nonce_bytes() as u64) as usize
+}
+
+/// nonce range from start fit in the bytes, ex. size of a new disk
+pub fn range_in_bytes(params: &PocParams, start: usize, bytes: u64) -> Range<usize> {
+    start..start + nonces_in_bytes(params, bytes)
+}
+
+/// free bytes of the file system of the path, for unprivileged user
+pub fn free_space(path: &Path) -> Result<u64, PocError> {
+    Ok(fs2::available_space(path)?)
+}
+
+/// nonces fit in the free space of the path, leave reserve bytes free
+pub fn nonces_in_free_space(
+    params: &PocParams,
+    path: &Path,
+    reserve: u64,
+) -> Result<usize, PocError> {
+    let free = free_space(path)?.saturating_sub(reserve);
+    Ok(nonces_in_bytes(params, free))
+}
+
+/// bytes written to out_dir by converting, inputs are kept until finished
+///
+/// so the disk of unoptimized files in out_dir needs twice of the plot size
+pub fn convert_bytes(params: &PocParams, nonces: usize) -> u64 {
+    plot_bytes(params, nonces)
+}
+
+/// error if free space of the dir is less than needed
+pub(crate) fn check_space(dir: &Path, needed: u64) -> Result<(), PocError> {
+    let available = free_space(dir)?;
+    if available < needed {
+        return Err(PocError::NoSpace { needed, available });
+    }
+    Ok(())
+}
+
+/// bytes to allocate for the file growing to len, blocks already held by the
+/// file (ex. preallocated tmp file of resumed plotting) are not needed again
+pub(crate) fn needed_space(fs: &File, len: u64) -> Result<u64, PocError> {
+    Ok(len.saturating_sub(fs.allocated_size()?))
+}
@@ -0,0 +1,291 @@
+use crate::cancel::CancelToken;
+use crate::capacity::plot_bytes;
+use crate::error::PocError;
+use crate::plotfile::{PlotFile, PlotFlag};
+use crate::progress::{Phase, ProgressSink, ProgressTracker};
+use crate::reader::PlotReader;
+use crate::storage::PlotStorage;
+use crate::PocParams;
+use std::ops::Range;
+
+/// head of the checksum trailer
+const MAGIC: &[u8; 8] = b"POCSUM02";
+/// bytes of magic and block_nonces
+pub const HEADER_LEN: usize = 16;
+/// bytes of a checksum, head of blake3 hash
+pub const CHECKSUM_LEN: usize = 8;
+
+/// checksum trailer placed after the plot data, optional for both layouts
+///
+/// `[magic][block_nonces: u64 le][scope0: block0, block1,..]-[scope1: ..]-..`
+///
+/// a checksum is over scope hashes of the block nonces in nonce order, so
+/// the trailer does not depend on the layout. checksums of a scope are
+/// contiguous, a scope read by seeking is verified by one more small read
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Checksums {
+    /// nonces of a block, the last block may be shorter
+    pub block_nonces: usize,
+    pub nonces: usize,
+    pub scope_count: usize,
+    /// position of the trailer, bytes of the plot data
+    pub offset: u64,
+}
+
+impl Checksums {
+    pub fn new(params: &PocParams, nonces: usize, block_nonces: usize) -> Self {
+        assert!(0 < block_nonces);
+        Checksums {
+            block_nonces,
+            nonces,
+            scope_count: params.scope_count(),
+            offset: plot_bytes(params, nonces),
+        }
+    }
+
+    /// trailer of optional block size, ex. `PlotterConfig::checksum_nonces`
+    pub fn with(params: &PocParams, nonces: usize, block_nonces: Option<usize>) -> Option<Self> {
+        block_nonces.map(|block_nonces| Checksums::new(params, nonces, block_nonces))
+    }
+
+    /// read the trailer after plot data, none if the storage holds only the data
+    pub fn read(
+        storage: &dyn PlotStorage,
+        params: &PocParams,
+        nonces: usize,
+    ) -> Result<Option<Self>, PocError> {
+        let offset = plot_bytes(params, nonces);
+        let size = storage.size()?;
+        if size <= offset {
+            return Ok(None);
+        }
+        let mut header = [0u8; HEADER_LEN];
+        storage.read_at(offset, &mut header)?;
+        Checksums::from_header(params, nonces, size, &header).map(Some)
+    }
+
+    /// check the trailer header of the storage of size bytes
+    pub(crate) fn from_header(
+        params: &PocParams,
+        nonces: usize,
+        size: u64,
+        header: &[u8],
+    ) -> Result<Self, PocError> {
+        let mut block_nonces = [0u8; 8];
+        block_nonces.copy_from_slice(&header[8..HEADER_LEN]);
+        let block_nonces = u64::from_le_bytes(block_nonces) as usize;
+        if &header[..8] != MAGIC || block_nonces == 0 {
+            return Err(PocError::Io("broken checksum trailer".to_owned()));
+        }
+        let table = Checksums::new(params, nonces, block_nonces);
+        if size != table.offset + table.trailer_bytes() {
+            return Err(PocError::Io(format!(
+                "plot is {}bytes, expect {}bytes with checksums",
+                size,
+                table.offset + table.trailer_bytes()
+            )));
+        }
+        Ok(table)
+    }
+
+    pub fn blocks(&self) -> usize {
+        self.nonces.div_ceil(self.block_nonces)
+    }
+
+    /// bytes of the trailer
+    pub fn trailer_bytes(&self) -> u64 {
+        (HEADER_LEN + self.blocks() * self.scope_count * CHECKSUM_LEN) as u64
+    }
+
+    /// position of the checksum of the scope of the block
+    pub fn position(&self, block: usize, scope: usize) -> u64 {
+        let index = scope * self.blocks() + block;
+        self.offset + (HEADER_LEN + index * CHECKSUM_LEN) as u64
+    }
+
+    /// nonce indexes of the block (from start of the plot)
+    pub fn block_range(&self, block: usize) -> Range<usize> {
+        let first = block * self.block_nonces;
+        first..self.nonces.min(first + self.block_nonces)
+    }
+
+    pub(crate) fn write_header(&self, storage: &mut dyn PlotStorage) -> Result<(), PocError> {
+        let mut header = [0u8; HEADER_LEN];
+        header[..8].copy_from_slice(MAGIC);
+        header[8..].copy_from_slice(&(self.block_nonces as u64).to_le_bytes());
+        storage.write_at(self.offset, &header)
+    }
+}
+
+/// checksum of scope hashes
+pub fn checksum(hashes: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let mut sum = [0u8; CHECKSUM_LEN];
+    sum.copy_from_slice(&blake3::hash(hashes).as_bytes()[..CHECKSUM_LEN]);
+    sum
+}
+
+/// checksums of unoptimized nonces starting at a block, `[scope][block]` ordered
+pub(crate) fn nonce_checksums(params: &PocParams, data: &[u8], block_nonces: usize) -> Vec<u8> {
+    let nonce_bytes = params.nonce_bytes();
+    let nonces = data.len() / nonce_bytes;
+    let blocks = nonces.div_ceil(block_nonces);
+    let mut sums = vec![0u8; blocks * params.scope_count() * CHECKSUM_LEN];
+    for (block, first) in (0..nonces).step_by(block_nonces).enumerate() {
+        let data = &data[first * nonce_bytes..nonces.min(first + block_nonces) * nonce_bytes];
+        for scope in 0..params.scope_count() {
+            let mut hasher = blake3::Hasher::new();
+            for nonce in data.chunks(nonce_bytes) {
+                hasher.update(&nonce[scope * 32..scope * 32 + 32]);
+            }
+            let dst = (scope * blocks + block) * CHECKSUM_LEN;
+            sums[dst..dst + CHECKSUM_LEN]
+                .copy_from_slice(&hasher.finalize().as_bytes()[..CHECKSUM_LEN]);
+        }
+    }
+    sums
+}
+
+/// checksums of a band of scope rows fed in nonce order, ex. tiles of converter
+pub(crate) struct BandChecksums {
+    table: Checksums,
+    first_scope: usize,
+    hashers: Vec<blake3::Hasher>,
+    /// `[scope][block]` checksums of the band
+    sums: Vec<u8>,
+}
+
+impl BandChecksums {
+    pub fn new(table: Checksums, first_scope: usize, scopes: usize) -> Self {
+        BandChecksums {
+            table,
+            first_scope,
+            hashers: vec![blake3::Hasher::new(); scopes],
+            sums: vec![0u8; table.blocks() * scopes * CHECKSUM_LEN],
+        }
+    }
+
+    /// scope hashes of the scope in band from the nonce index
+    pub fn feed(&mut self, scope: usize, index: usize, mut row: &[u8]) {
+        let mut index = index;
+        while !row.is_empty() {
+            let block = index / self.table.block_nonces;
+            let block_end = self.table.block_range(block).end;
+            let count = (row.len() / 32).min(block_end - index);
+            self.hashers[scope].update(&row[..count * 32]);
+            row = &row[count * 32..];
+            index += count;
+            if index == block_end {
+                let dst = (scope * self.table.blocks() + block) * CHECKSUM_LEN;
+                let hash = self.hashers[scope].finalize();
+                self.sums[dst..dst + CHECKSUM_LEN]
+                    .copy_from_slice(&hash.as_bytes()[..CHECKSUM_LEN]);
+                self.hashers[scope].reset();
+            }
+        }
+    }
+
+    /// write checksums of the band by one span, all nonces must be fed
+    pub fn write(&self, storage: &mut dyn PlotStorage) -> Result<(), PocError> {
+        storage.write_at(self.table.position(0, self.first_scope), &self.sums)
+    }
+}
+
+/// nonce indexes (from start of the plot) of blocks in a scope row mismatched
+/// with `[block]` checksums of the scope
+pub(crate) fn corrupted_blocks(table: &Checksums, sums: &[u8], row: &[u8]) -> Vec<Range<usize>> {
+    let mut corrupted = vec![];
+    for (block, sum) in sums.chunks(CHECKSUM_LEN).enumerate() {
+        let range = table.block_range(block);
+        if checksum(&row[range.start * 32..range.end * 32]) != sum {
+            corrupted.push(range);
+        }
+    }
+    corrupted
+}
+
+/// verify a band of scope rows read from an optimized file before written to others
+///
+/// `PocError::Corrupted` of nonces from `start` if any block mismatched
+pub(crate) fn verify_band(
+    storage: &dyn PlotStorage,
+    table: &Checksums,
+    start: usize,
+    first_scope: usize,
+    band: &[u8],
+) -> Result<(), PocError> {
+    let row = 32 * table.nonces;
+    let len = table.blocks() * CHECKSUM_LEN;
+    let mut sums = vec![0u8; band.len() / row * len];
+    storage.read_at(table.position(0, first_scope), &mut sums)?;
+    let corrupted = band
+        .chunks(row)
+        .zip(sums.chunks(len))
+        .flat_map(|(row, sums)| corrupted_blocks(table, sums, row))
+        .map(|range| start + range.start..start + range.end)
+        .collect::<Vec<Range<usize>>>();
+    if corrupted.is_empty() {
+        Ok(())
+    } else {
+        Err(PocError::Corrupted(join_ranges(corrupted)))
+    }
+}
+
+/// sort and join nonce ranges
+pub(crate) fn join_ranges(mut ranges: Vec<Range<usize>>) -> Vec<Range<usize>> {
+    ranges.sort_by_key(|range| range.start);
+    let mut joined: Vec<Range<usize>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match joined.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => joined.push(range),
+        }
+    }
+    joined
+}
+
+/// verify all checksums of the plot file, return corrupted nonce ranges to replot
+///
+/// optimized file is read by scope rows, unoptimized by blocks.
+/// `PocError::Invalid` if the file has no checksums or is not of its version
+pub fn scrub_plot(
+    plot: &PlotFile,
+    progress: &dyn ProgressSink,
+    cancel: &CancelToken,
+) -> Result<Vec<Range<usize>>, PocError> {
+    let reader = PlotReader::from_plot(plot)?;
+    reader.check_version(&plot.addr)?;
+    let table = reader
+        .checksums()?
+        .ok_or_else(|| PocError::Invalid(format!("no checksums in {:?}", plot)))?;
+    let total_bytes = table.offset + table.trailer_bytes();
+    let mut tracker = ProgressTracker::start(progress, Phase::Scrubbing, table.nonces, total_bytes);
+    let mut corrupted = vec![];
+    match plot.flag {
+        PlotFlag::Optimized => {
+            for scope in 0..table.scope_count {
+                if cancel.is_cancelled() {
+                    return Err(PocError::Cancelled);
+                }
+                let hashes = reader.scope_slice_cancellable(scope, cancel)?;
+                corrupted.extend(reader.verify_scope(scope, &hashes)?);
+                let bytes = (hashes.len() + table.blocks() * CHECKSUM_LEN) as u64;
+                tracker.chunk_done(table.nonces, bytes);
+            }
+        }
+        PlotFlag::Unoptimized => {
+            for block in 0..table.blocks() {
+                if cancel.is_cancelled() {
+                    return Err(PocError::Cancelled);
+                }
+                let range = table.block_range(block);
+                if !reader.verify_block(block)? {
+                    corrupted.push(plot.start + range.start..plot.start + range.end);
+                }
+                let bytes = plot_bytes(&plot.params, range.len())
+                    + (table.scope_count * CHECKSUM_LEN) as u64;
+                tracker.chunk_done(range.len(), bytes);
+            }
+        }
+    }
+    Ok(join_ranges(corrupted))
+}
@@ -0,0 +1,55 @@
+use std::fmt;
+use std::io;
+use std::ops::Range;
+
+/// error of plotting, converting and seeking
+#[derive(Clone, Debug, PartialEq)]
+pub enum PocError {
+    /// stopped by CancelToken, resumable state is left
+    Cancelled,
+    /// full seeked but not found enough work
+    NotFound,
+    /// file or data error
+    Io(String),
+    /// wrong plot files or arguments
+    Invalid(String),
+    /// checked before writing, nothing is written
+    NoSpace { needed: u64, available: u64 },
+    /// file is used by other plotting, converting or seeking process
+    Locked(String),
+    /// checksums mismatched, replot the nonce ranges. seekers skip corrupted
+    /// nonces and fail only if none is left
+    Corrupted(Vec<Range<usize>>),
+}
+
+impl fmt::Display for PocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PocError::Cancelled => write!(f, "cancelled by user"),
+            PocError::NotFound => write!(f, "full seeked but not found enough work"),
+            PocError::Io(err) => write!(f, "{}", err),
+            PocError::Invalid(err) => write!(f, "invalid: {}", err),
+            PocError::NoSpace { needed, available } => write!(
+                f,
+                "not enough disk space, need {}bytes but {}bytes free",
+                needed, available
+            ),
+            PocError::Locked(path) => write!(f, "locked by other process: {}", path),
+            PocError::Corrupted(ranges) => {
+                let ranges = ranges
+                    .iter()
+                    .map(|range| format!("{}-{}", range.start, range.end))
+                    .collect::<Vec<String>>();
+                write!(f, "corrupted nonces {}", ranges.join(", "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for PocError {}
+
+impl From<io::Error> for PocError {
+    fn from(err: io::Error) -> Self {
+        PocError::Io(err.to_string())
+    }
+}
@@ -0,0 +1,110 @@
+use crate::cancel::CancelToken;
+use crate::error::PocError;
+use crate::lock::lock_exclusive;
+use crate::plotfile::{
+    convert_range, plot_stem, read_journal, ConverterConfig, PlotFile, PlotFlag,
+};
+use crate::progress::ProgressSink;
+use crate::storage::FileStorage;
+use std::fs::{remove_file, rename, File, OpenOptions};
+use std::io::Write;
+use std::ops::Range;
+
+/// optimize an unoptimized file on a full disk, without space for a copy
+///
+/// segments of segment_nonces are cut from the end of the file: each is
+/// converted to an optimized file next to it, then the unoptimized file is
+/// truncated. spare space is one segment, ex. `nonces_in_free_space`.
+/// optimized files are returned in nonce order, merge them when space allows.
+///
+/// while converting the file is renamed to XX.inplace and cut segments are
+/// recorded to a XX.inplace.resume journal, so converting cancelled (or killed)
+/// restarts from the left segments by same arguments, left jobs are listed
+/// in `PlotScan::pending`. the journal is not
+/// XX.resume of plotting the same range, removed by `clean_stale_tmp`
+pub fn optimize_in_place(
+    plot: &PlotFile,
+    segment_nonces: usize,
+    config: &ConverterConfig,
+    progress: &dyn ProgressSink,
+    cancel: &CancelToken,
+) -> Result<Vec<PlotFile>, PocError> {
+    assert!(0 < segment_nonces);
+    if plot.flag != PlotFlag::Unoptimized {
+        return Err(PocError::Invalid(format!("cannot optimize {:?}", plot)));
+    }
+    let dir = plot.path.parent().unwrap();
+    let work = plot.path.with_extension("inplace");
+    let journal_path = plot.path.with_extension("inplace.resume");
+
+    // take the file from seekers, or reopen to resume
+    let resume = work.exists();
+    let path = if resume { &work } else { &plot.path };
+    let fs = OpenOptions::new().read(true).write(true).open(path)?;
+    lock_exclusive(&fs, path)?;
+    if !resume {
+        File::create(&journal_path)?;
+        rename(&plot.path, &work)?;
+    }
+    let mut journal = OpenOptions::new().append(true).open(&journal_path)?;
+    let mut segments = read_journal(&journal_path)
+        .into_iter()
+        .map(|(start, end)| start..end)
+        .collect::<Vec<Range<usize>>>();
+
+    // killed after recorded but before truncated
+    let nonce_bytes = plot.params.nonce_bytes() as u64;
+    let mut end = segments
+        .iter()
+        .map(|segment| segment.start)
+        .min()
+        .unwrap_or(plot.end);
+    fs.set_len((end - plot.start) as u64 * nonce_bytes)?;
+
+    // cut segments from the end
+    let source = PlotFile {
+        path: work.clone(),
+        ..plot.clone()
+    };
+    let optimized = |segment: Range<usize>| {
+        let name = plot_stem(
+            &PlotFlag::Optimized,
+            &plot.addr,
+            segment.start,
+            segment.end,
+            plot.version,
+        );
+        PlotFile {
+            flag: PlotFlag::Optimized,
+            path: dir.join(format!("{}.dat", name)),
+            start: segment.start,
+            end: segment.end,
+            ..plot.clone()
+        }
+    };
+    while plot.start < end {
+        let start = end.saturating_sub(segment_nonces).max(plot.start);
+        let dst = optimized(start..end).path;
+        if !dst.exists() {
+            // note: the converter resumes the segment by its own journal
+            let files = std::slice::from_ref(&source);
+            let reader = FileStorage::new(fs.try_clone()?);
+            convert_range(files, &[&reader], start..end, dir, config, progress, cancel)?;
+        }
+
+        // segment must reach the disk before its source is cut
+        File::open(&dst)?.sync_all()?;
+        writeln!(journal, "{}-{}", start, end)?;
+        journal.sync_data()?;
+        fs.set_len((start - plot.start) as u64 * nonce_bytes)?;
+        segments.push(start..end);
+        end = start;
+    }
+
+    // all nonces moved to optimized files
+    remove_file(&work)?;
+    remove_file(&journal_path)?;
+    std::mem::drop(fs);
+    segments.sort_by_key(|segment| segment.start);
+    Ok(segments.into_iter().map(optimized).collect())
+}
@@ -0,0 +1,91 @@
+use crate::error::PocError;
+use fs2::FileExt;
+use std::fs::{read_dir, remove_file, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+// advisory locks between plotting, converting and seeking processes
+//
+// writers hold an exclusive flock on their XX.tmp until renamed to XX.dat,
+// readers of a plot hold a shared flock on the XX.dat. locks are released
+// by the kernel when the holder exits, even killed
+
+fn locked_error(path: &Path, err: io::Error) -> PocError {
+    if err.raw_os_error() == fs2::lock_contended_error().raw_os_error() {
+        PocError::Locked(path.display().to_string())
+    } else {
+        PocError::Io(format!("error on locking {}: {}", path.display(), err))
+    }
+}
+
+/// open plot file to read with shared lock, fails if a writer holds it
+pub(crate) fn open_shared(path: &Path) -> Result<File, PocError> {
+    let fs = File::open(path)?;
+    FileExt::try_lock_shared(&fs).map_err(|err| locked_error(path, err))?;
+    Ok(fs)
+}
+
+/// open or create tmp file with exclusive lock, fails if other writer holds it
+///
+/// contents are kept for resume, truncate by `set_len(0)` to restart. the
+/// file is not truncated before locked, so a running writer is never broken
+pub(crate) fn open_exclusive(path: &Path) -> Result<File, PocError> {
+    let fs = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    lock_exclusive(&fs, path)?;
+    Ok(fs)
+}
+
+/// take exclusive lock of the opened file, fails if other process uses it
+pub(crate) fn lock_exclusive(fs: &File, path: &Path) -> Result<(), PocError> {
+    FileExt::try_lock_exclusive(fs).map_err(|err| locked_error(path, err))
+}
+
+/// true if other process reads or writes the file now
+pub fn is_locked(path: &Path) -> Result<bool, PocError> {
+    let fs = File::open(path)?;
+    match FileExt::try_lock_exclusive(&fs) {
+        Ok(()) => Ok(false),
+        Err(err) => match locked_error(path, err) {
+            PocError::Locked(_) => Ok(true),
+            err => Err(err),
+        },
+    }
+}
+
+/// remove XX.tmp and XX.resume files left by dead writers in the dir
+///
+/// files of running writers are kept. note: resumable progress of the
+/// removed files is lost, plot the same range again to resume instead
+pub fn clean_stale_tmp(dir: &Path) -> Result<Vec<PathBuf>, PocError> {
+    let mut removed = vec![];
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() || path.extension().is_none_or(|ext| ext != "tmp") {
+            continue;
+        }
+        let name = path.file_name().unwrap().to_string_lossy();
+        if !(name.starts_with("unoptimized.") || name.starts_with("optimized.")) {
+            continue;
+        }
+        // hold the lock until removed, so no writer starts on it meanwhile
+        let fs = match open_exclusive(&path) {
+            Ok(fs) => fs,
+            Err(PocError::Locked(_)) => continue,
+            Err(err) => return Err(err),
+        };
+        let journal = path.with_extension("resume");
+        if journal.exists() {
+            remove_file(&journal)?;
+            removed.push(journal);
+        }
+        remove_file(&path)?;
+        drop(fs);
+        removed.push(path);
+    }
+    Ok(removed)
+}
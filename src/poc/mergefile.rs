@@ -0,0 +1,292 @@
+use crate::cancel::CancelToken;
+use crate::capacity::plot_bytes;
+use crate::checksum::{verify_band, BandChecksums, Checksums};
+use crate::error::PocError;
+use crate::lock::{open_exclusive, open_shared};
+use crate::plotfile::{plot_stem, ConverterConfig, PlotFile, PlotFlag};
+use crate::progress::{Phase, ProgressSink, ProgressTracker};
+use crate::storage::{FileStorage, PlotStorage};
+use crate::{PocParams, PocVersion};
+use std::cmp::min;
+use std::fs::rename;
+use std::path::{Path, PathBuf};
+
+/// concat adjacent optimized files to one optimized file
+///
+/// inputs are left, remove them after success. converting cancelled
+/// restarts from the beginning. `PocError::Corrupted` if an input mismatched
+/// its checksums, replot the nonces first
+pub fn merge_optimized(
+    files: Vec<PlotFile>,
+    out_dir: &Path,
+    config: &ConverterConfig,
+    progress: &dyn ProgressSink,
+    cancel: &CancelToken,
+) -> Result<PlotFile, PocError> {
+    assert!(out_dir.is_dir());
+    if files.len() < 2 {
+        return Err(PocError::Invalid(
+            "merge requires two files at least".to_owned(),
+        ));
+    }
+
+    // check inputs status
+    let addr = files.first().unwrap().addr.clone();
+    let start = files.first().unwrap().start;
+    let end = files.last().unwrap().end;
+    let version = files.first().unwrap().version;
+    let params = files.first().unwrap().params;
+    for (index, plot) in files.iter().enumerate() {
+        if plot.flag != PlotFlag::Optimized
+            || plot.addr != addr
+            || plot.version != version
+            || plot.params != params
+        {
+            return Err(PocError::Invalid(format!("cannot merge {:?}", plot)));
+        }
+        if 0 < index && plot.start != files[index - 1].end {
+            return Err(PocError::Invalid(format!("not adjacent {:?}", plot)));
+        }
+    }
+
+    // create file objects
+    let reader = files
+        .iter()
+        .map(|plot| Ok(FileStorage::new(open_shared(&plot.path)?)))
+        .collect::<Result<Vec<FileStorage>, PocError>>()?;
+    let tables = reader
+        .iter()
+        .zip(files.iter())
+        .map(|(fs, plot)| Checksums::read(fs, &plot.params, plot.end - plot.start))
+        .collect::<Result<Vec<Option<Checksums>>, PocError>>()?;
+    let (tmp, dst) = output_paths(out_dir, &addr, start, end, version);
+    let nonces = end - start;
+    let (mut writer, checksums) = create_tmp(&tmp, &params, nonces, config)?;
+
+    // read bands of scopes and write rows of all files
+    let scope_count = params.scope_count();
+    let band_scopes = band_scopes(config, nonces, scope_count);
+    let mut buffers = files
+        .iter()
+        .map(|plot| vec![0u8; band_scopes * 32 * (plot.end - plot.start)])
+        .collect::<Vec<Vec<u8>>>();
+    let total_bytes = (scope_count * nonces * 32) as u64;
+    let mut tracker = ProgressTracker::start(progress, Phase::Converting, nonces, total_bytes);
+    for first_scope in (0..scope_count).step_by(band_scopes) {
+        if cancel.is_cancelled() {
+            return Err(PocError::Cancelled);
+        }
+        let scopes = min(band_scopes, scope_count - first_scope);
+        let inputs = reader.iter().zip(tables.iter()).zip(files.iter());
+        for (((fs, table), plot), buffer) in inputs.zip(buffers.iter_mut()) {
+            let row = 32 * (plot.end - plot.start);
+            fs.read_at((first_scope * row) as u64, &mut buffer[..scopes * row])?;
+            if let Some(table) = table {
+                verify_band(fs, table, plot.start, first_scope, &buffer[..scopes * row])?;
+            }
+        }
+        let mut sums = checksums.map(|table| BandChecksums::new(table, first_scope, scopes));
+        for scope in 0..scopes {
+            let mut pos = (first_scope + scope) * nonces * 32;
+            for (plot, buffer) in files.iter().zip(buffers.iter()) {
+                let row = 32 * (plot.end - plot.start);
+                let piece = &buffer[scope * row..(scope + 1) * row];
+                writer.write_at(pos as u64, piece)?;
+                if let Some(sums) = &mut sums {
+                    sums.feed(scope, plot.start - start, piece);
+                }
+                pos += row;
+            }
+        }
+        if let Some(sums) = &sums {
+            sums.write(&mut writer)?;
+        }
+
+        // show progress
+        tracker.chunk_done(nonces, (scopes * nonces * 32) as u64);
+    }
+    tracker.phase_changed(Phase::Finalizing);
+
+    // rename XX.tmp to XX.dat, then release locks
+    finish_tmp(&mut writer, &params, nonces, checksums)?;
+    rename(&tmp, &dst)?;
+    std::mem::drop(writer);
+    std::mem::drop(reader);
+
+    Ok(PlotFile {
+        flag: PlotFlag::Optimized,
+        path: dst,
+        addr,
+        start,
+        end,
+        version,
+        params,
+    })
+}
+
+/// split an optimized file at the nonce to [start, at) and [at, end) files
+///
+/// input is left, remove it after success. lower and upper file can be
+/// written to other disks. `PocError::Corrupted` if the input mismatched its
+/// checksums
+pub fn split_optimized(
+    file: &PlotFile,
+    at: usize,
+    lower_dir: &Path,
+    upper_dir: &Path,
+    config: &ConverterConfig,
+    progress: &dyn ProgressSink,
+    cancel: &CancelToken,
+) -> Result<(PlotFile, PlotFile), PocError> {
+    assert!(lower_dir.is_dir());
+    assert!(upper_dir.is_dir());
+    if file.flag != PlotFlag::Optimized {
+        return Err(PocError::Invalid(format!("cannot split {:?}", file)));
+    }
+    if at <= file.start || file.end <= at {
+        return Err(PocError::Invalid(format!("split {} out of {:?}", at, file)));
+    }
+
+    // create file objects
+    let reader = FileStorage::new(open_shared(&file.path)?);
+    let table = Checksums::read(&reader, &file.params, file.end - file.start)?;
+    let (lower_tmp, lower_dst) = output_paths(lower_dir, &file.addr, file.start, at, file.version);
+    let (upper_tmp, upper_dst) = output_paths(upper_dir, &file.addr, at, file.end, file.version);
+    let (mut lower, lower_checksums) =
+        create_tmp(&lower_tmp, &file.params, at - file.start, config)?;
+    let (mut upper, upper_checksums) = create_tmp(&upper_tmp, &file.params, file.end - at, config)?;
+
+    // read bands of scopes and write each half of rows
+    let nonces = file.end - file.start;
+    let row = 32 * nonces;
+    let lower_row = 32 * (at - file.start);
+    let scope_count = file.params.scope_count();
+    let band_scopes = band_scopes(config, nonces, scope_count);
+    let mut buffer = vec![0u8; band_scopes * row];
+    let total_bytes = (scope_count * row) as u64;
+    let mut tracker = ProgressTracker::start(progress, Phase::Converting, nonces, total_bytes);
+    for first_scope in (0..scope_count).step_by(band_scopes) {
+        if cancel.is_cancelled() {
+            return Err(PocError::Cancelled);
+        }
+        let scopes = min(band_scopes, scope_count - first_scope);
+        reader.read_at((first_scope * row) as u64, &mut buffer[..scopes * row])?;
+        if let Some(table) = &table {
+            verify_band(
+                &reader,
+                table,
+                file.start,
+                first_scope,
+                &buffer[..scopes * row],
+            )?;
+        }
+        let band = |table| BandChecksums::new(table, first_scope, scopes);
+        let mut lower_sums = lower_checksums.map(band);
+        let mut upper_sums = upper_checksums.map(band);
+        for scope in 0..scopes {
+            let first = scope * row;
+            let lower_pos = (first_scope + scope) * lower_row;
+            let upper_pos = (first_scope + scope) * (row - lower_row);
+            let (lower_piece, upper_piece) = buffer[first..first + row].split_at(lower_row);
+            lower.write_at(lower_pos as u64, lower_piece)?;
+            upper.write_at(upper_pos as u64, upper_piece)?;
+            if let (Some(lower_sums), Some(upper_sums)) = (&mut lower_sums, &mut upper_sums) {
+                lower_sums.feed(scope, 0, lower_piece);
+                upper_sums.feed(scope, 0, upper_piece);
+            }
+        }
+        if let (Some(lower_sums), Some(upper_sums)) = (&lower_sums, &upper_sums) {
+            lower_sums.write(&mut lower)?;
+            upper_sums.write(&mut upper)?;
+        }
+
+        // show progress
+        tracker.chunk_done(nonces, (scopes * row) as u64);
+    }
+    tracker.phase_changed(Phase::Finalizing);
+
+    // rename XX.tmp to XX.dat, then release locks
+    finish_tmp(&mut lower, &file.params, at - file.start, lower_checksums)?;
+    finish_tmp(&mut upper, &file.params, file.end - at, upper_checksums)?;
+    rename(&lower_tmp, &lower_dst)?;
+    rename(&upper_tmp, &upper_dst)?;
+    std::mem::drop(lower);
+    std::mem::drop(upper);
+    std::mem::drop(reader);
+
+    let lower = PlotFile {
+        flag: PlotFlag::Optimized,
+        path: lower_dst,
+        addr: file.addr.clone(),
+        start: file.start,
+        end: at,
+        version: file.version,
+        params: file.params,
+    };
+    let upper = PlotFile {
+        flag: PlotFlag::Optimized,
+        path: upper_dst,
+        addr: file.addr.clone(),
+        start: at,
+        end: file.end,
+        version: file.version,
+        params: file.params,
+    };
+    Ok((lower, upper))
+}
+
+/// scopes read at once within memory limit
+fn band_scopes(config: &ConverterConfig, nonces: usize, scope_count: usize) -> usize {
+    (config.max_memory / (32 * nonces)).clamp(1, scope_count)
+}
+
+/// locked and truncated tmp file, merging restarts from the beginning
+///
+/// checksum header is written if `checksum_nonces`
+fn create_tmp(
+    path: &Path,
+    params: &PocParams,
+    nonces: usize,
+    config: &ConverterConfig,
+) -> Result<(FileStorage, Option<Checksums>), PocError> {
+    let fs = open_exclusive(path)?;
+    fs.set_len(0)?;
+    let checksums = Checksums::with(params, nonces, config.checksum_nonces);
+    let len = output_bytes(params, nonces, checksums);
+    let mut storage = FileStorage::output(fs, path, len, config.preallocate, config.direct_io)?;
+    if let Some(table) = &checksums {
+        table.write_header(&mut storage)?;
+    }
+    Ok((storage, checksums))
+}
+
+/// cut preallocated bytes to the exact size and sync, before renamed to XX.dat
+fn finish_tmp(
+    storage: &mut FileStorage,
+    params: &PocParams,
+    nonces: usize,
+    checksums: Option<Checksums>,
+) -> Result<(), PocError> {
+    storage.set_len(output_bytes(params, nonces, checksums))?;
+    storage.sync()
+}
+
+/// plot and checksum trailer bytes of output file
+fn output_bytes(params: &PocParams, nonces: usize, checksums: Option<Checksums>) -> u64 {
+    plot_bytes(params, nonces) + checksums.map_or(0, |table| table.trailer_bytes())
+}
+
+/// (XX.tmp, XX.dat) of optimized file
+fn output_paths(
+    dir: &Path,
+    addr: &[u8],
+    start: usize,
+    end: usize,
+    version: PocVersion,
+) -> (PathBuf, PathBuf) {
+    let name = plot_stem(&PlotFlag::Optimized, addr, start, end, version);
+    (
+        dir.join(format!("{}.tmp", name)),
+        dir.join(format!("{}.dat", name)),
+    )
+}
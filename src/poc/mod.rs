@@ -1,121 +1,452 @@
+pub mod allocator;
+pub mod cancel;
+pub mod capacity;
+pub mod checksum;
+pub mod error;
+pub mod inplace;
+pub mod lock;
+pub mod mergefile;
+pub mod plotfarm;
 pub mod plotfile;
+pub mod progress;
+pub mod reader;
 pub mod seekfile;
+pub mod storage;
+pub mod verifier;
 
 use bigint::U256;
-use blake2b_simd::blake2b;
+use blake2b_simd::{blake2b, many, Params};
+use error::PocError;
 use std::cmp::min;
+use verifier::PocVerifier;
 
 pub const LOOP_COUNT: usize = 8192;
 pub const HASH_LEN: usize = 64;
 pub const SEED_LEN: usize = 21 + 4; // addr + nonce
 pub const TOTAL_LEN: usize = SEED_LEN + LOOP_COUNT * HASH_LEN;
+pub const SCOPE_COUNT: usize = LOOP_COUNT * HASH_LEN / 32; // 32 bytes scopes of a nonce
+pub const POC_LANES: usize = 8; // nonces hashed in lockstep, two AVX2 passes
+pub const POC_V2_HEIGHT: u32 = u32::MAX; // not scheduled yet
+
+/// hash function of poc, plot geometry is same for all versions
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PocVersion {
+    /// blake2b hash chain and work
+    #[default]
+    V1,
+    /// blake3 hash chain and work
+    V2,
+}
+
+impl PocVersion {
+    /// version of the block height
+    pub fn from_height(height: u32) -> Self {
+        if height < POC_V2_HEIGHT {
+            PocVersion::V1
+        } else {
+            PocVersion::V2
+        }
+    }
+
+    /// plot file name suffix before extension, v1 has no suffix
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            PocVersion::V1 => "",
+            PocVersion::V2 => ".v2",
+        }
+    }
+
+    /// version of the file name suffix, ex. ".v2"
+    pub fn from_suffix(suffix: &str) -> Option<Self> {
+        match suffix {
+            "" => Some(PocVersion::V1),
+            ".v2" => Some(PocVersion::V2),
+            _ => None,
+        }
+    }
+
+    /// fill the hash of the chain, 64 bytes at most
+    fn chain_hash(&self, input: &[u8], hash: &mut [u8]) {
+        match self {
+            PocVersion::V1 => hash.clone_from_slice(&blake2b(input).as_bytes()[..hash.len()]),
+            PocVersion::V2 => blake3::Hasher::new()
+                .update(input)
+                .finalize_xof()
+                .fill(hash),
+        }
+    }
+
+    /// 32 bytes work hash
+    pub(crate) fn work_hash(&self, input: &[u8]) -> [u8; 32] {
+        let mut hash = [0u8; 32];
+        match self {
+            PocVersion::V1 => hash.clone_from_slice(&blake2b(input).as_bytes()[0..32]),
+            PocVersion::V2 => hash = *blake3::hash(input).as_bytes(),
+        }
+        hash
+    }
+}
+
+/// geometry of poc hashes, mainnet by default
+///
+/// small params make tiny plots for testnets and tests
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PocParams {
+    loop_count: usize,
+    hash_len: usize,
+    addr_len: usize,
+}
+
+impl Default for PocParams {
+    fn default() -> Self {
+        PocParams::MAINNET
+    }
+}
+
+impl PocParams {
+    /// 8192 hashes of 64 bytes (512KiB a nonce) and 21 bytes address
+    pub const MAINNET: PocParams = PocParams {
+        loop_count: LOOP_COUNT,
+        hash_len: HASH_LEN,
+        addr_len: 21,
+    };
+
+    /// error if a nonce is not made of 32 bytes scopes or hash is over 64 bytes
+    pub fn new(loop_count: usize, hash_len: usize, addr_len: usize) -> Result<Self, PocError> {
+        if loop_count == 0 || hash_len == 0 || 64 < hash_len || addr_len == 0 {
+            return Err(PocError::Invalid(format!(
+                "params loop_count={} hash_len={} addr_len={}",
+                loop_count, hash_len, addr_len
+            )));
+        }
+        if !(loop_count * hash_len).is_multiple_of(32) {
+            return Err(PocError::Invalid(format!(
+                "nonce {}bytes is not 32 bytes scopes",
+                loop_count * hash_len
+            )));
+        }
+        Ok(PocParams {
+            loop_count,
+            hash_len,
+            addr_len,
+        })
+    }
+
+    /// hashes chained for a nonce
+    pub fn loop_count(&self) -> usize {
+        self.loop_count
+    }
+
+    /// bytes of a chained hash
+    pub fn hash_len(&self) -> usize {
+        self.hash_len
+    }
+
+    pub fn addr_len(&self) -> usize {
+        self.addr_len
+    }
+
+    /// addr + nonce
+    pub fn seed_len(&self) -> usize {
+        self.addr_len + 4
+    }
+
+    /// bytes of a plotted nonce
+    pub fn nonce_bytes(&self) -> usize {
+        self.loop_count * self.hash_len
+    }
+
+    /// bytes of generator cache
+    pub fn total_len(&self) -> usize {
+        self.seed_len() + self.nonce_bytes()
+    }
+
+    /// 32 bytes scopes of a nonce
+    pub fn scope_count(&self) -> usize {
+        self.nonce_bytes() / 32
+    }
+
+    /// return boxed slice with filled zero for poc generator
+    pub fn generator_output(&self) -> Box<[u8]> {
+        vec![0u8; self.nonce_bytes()].into_boxed_slice()
+    }
+
+    /// return boxed slice with filled zero for poc generator
+    pub fn generator_cache(&self) -> Box<[u8]> {
+        vec![0u8; self.total_len()].into_boxed_slice()
+    }
+
+    /// get scope index 0~scope_count
+    pub fn scope_index(&self, previous_hash: &[u8]) -> usize {
+        // index = (previous_hash to little endian 32bytes int) % scope_length
+        assert_eq!(previous_hash.len(), 32);
+        let mut previous_hash = previous_hash.to_owned();
+        previous_hash.reverse();
+        let val: U256 = previous_hash.as_slice().into();
+        let div: U256 = self.scope_count().into();
+        let index: u32 = (val % div).into();
+        index as usize
+    }
+}
 
 /// return boxed slice with filled zero for poc generator
 pub fn get_generator_output() -> Box<[u8]> {
-    let vec = [0u8; LOOP_COUNT * HASH_LEN].to_vec();
-    vec.into_boxed_slice()
+    PocParams::MAINNET.generator_output()
 }
 
 /// return boxed slice with filled zero for poc generator
 pub fn get_generator_cache() -> Box<[u8]> {
-    let vec = [0u8; TOTAL_LEN].to_vec();
-    vec.into_boxed_slice()
+    PocParams::MAINNET.generator_cache()
 }
 
 /// get full size poc hash
 pub fn poc_generator(addr: &[u8], nonce: u32, output: &mut Box<[u8]>, cache: &mut Box<[u8]>) {
-    assert_eq!(addr.len(), 21);
-    assert_eq!(output.len(), LOOP_COUNT * HASH_LEN);
-    assert_eq!(cache.len(), TOTAL_LEN);
+    poc_generator_by_version(
+        &PocParams::MAINNET,
+        PocVersion::V1,
+        addr,
+        nonce,
+        output,
+        cache,
+    );
+}
+
+/// get full size poc hash of the params and version
+pub fn poc_generator_by_version(
+    params: &PocParams,
+    version: PocVersion,
+    addr: &[u8],
+    nonce: u32,
+    output: &mut Box<[u8]>,
+    cache: &mut Box<[u8]>,
+) {
+    assert_eq!(addr.len(), params.addr_len);
+    assert_eq!(output.len(), params.nonce_bytes());
+    assert_eq!(cache.len(), params.total_len());
+    let final_hash = hash_chain(params, version, addr, nonce, cache);
+    xor_output(params, &final_hash, cache, output);
+}
+
+/// get full size poc hashes of continuous nonces from first_nonce
+///
+/// v1 chains of the nonces are hashed in lockstep by SIMD lanes, use POC_LANES
+/// caches for full speed. output is same as `poc_generator_by_version` nonce by nonce
+pub fn poc_generator_many(
+    params: &PocParams,
+    version: PocVersion,
+    addr: &[u8],
+    first_nonce: u32,
+    outputs: &mut [u8],
+    caches: &mut [Box<[u8]>],
+) {
+    let total_len = params.total_len();
+    assert_eq!(addr.len(), params.addr_len);
+    assert_eq!(outputs.len(), caches.len() * params.nonce_bytes());
+    assert!(caches.iter().all(|cache| cache.len() == total_len));
+    if version != PocVersion::V1 {
+        // note: blake3 has no public many-lane api, hash nonce by nonce
+        for ((output, cache), nonce) in outputs
+            .chunks_exact_mut(params.nonce_bytes())
+            .zip(caches.iter_mut())
+            .zip(first_nonce..)
+        {
+            let final_hash = hash_chain(params, version, addr, nonce, cache);
+            xor_output(params, &final_hash, cache, output);
+        }
+        return;
+    }
+
+    for ((outputs, caches), first) in outputs
+        .chunks_mut(POC_LANES * params.nonce_bytes())
+        .zip(caches.chunks_mut(POC_LANES))
+        .zip((first_nonce..).step_by(POC_LANES))
+    {
+        hash_lanes(params, addr, first, outputs, caches);
+    }
+}
 
+/// v1 hashes of up to POC_LANES nonces, jobs and hashes are kept on stack
+fn hash_lanes(
+    params: &PocParams,
+    addr: &[u8],
+    first_nonce: u32,
+    outputs: &mut [u8],
+    caches: &mut [Box<[u8]>],
+) {
+    let (hash_len, total_len) = (params.hash_len, params.total_len());
     // seed ..-[addr 21bytes]-[nonce 4bytes]
-    let bytes: [u8; 4] = nonce.to_le_bytes();
-    cache[(TOTAL_LEN - 4)..].clone_from_slice(&bytes);
-    cache[(TOTAL_LEN - SEED_LEN)..(TOTAL_LEN - 4)].clone_from_slice(addr);
-    //println!("source={:?}", &source[(TOTAL_LEN-SEED_LENGTH)..]);
+    for (index, cache) in caches.iter_mut().enumerate() {
+        let bytes: [u8; 4] = (first_nonce + index as u32).to_le_bytes();
+        cache[(total_len - 4)..].clone_from_slice(&bytes);
+        cache[(total_len - params.seed_len())..(total_len - 4)].clone_from_slice(addr);
+    }
 
-    // seed [hash(HASH_LENGTH)]-...-[hash0]-[addr 21bytes]-[nonce 4bytes]
-    // [hashN] = blake2bp([hash(N-1)]-...-[hash0]-[addr 21bytes]-[nonce 4bytes])
-    let start_index = TOTAL_LEN - SEED_LEN;
-    let mut final_hash = [0u8; HASH_LEN];
-    for index in 0..(LOOP_COUNT) {
-        let start = start_index - index * HASH_LEN;
-        let end = min(start + 1024, TOTAL_LEN);
-        let hash = blake2b(&cache[start..end]);
-        let hash = hash.as_bytes();
-        cache[(start - HASH_LEN)..start].clone_from_slice(&hash);
+    // same chain as hash_chain, every lane at same position
+    let blake2b_params = Params::new();
+    let start_index = total_len - params.seed_len();
+    let mut hashes = [[0u8; 64]; POC_LANES];
+    for index in 0..(params.loop_count + 1) {
+        let (start, end) = if index < params.loop_count {
+            let start = start_index - index * hash_len;
+            (start, min(start + 1024, total_len))
+        } else {
+            // final hash
+            (0, total_len)
+        };
+        let mut jobs: [Option<many::HashManyJob>; POC_LANES] = std::array::from_fn(|lane| {
+            caches
+                .get(lane)
+                .map(|cache| many::HashManyJob::new(&blake2b_params, &cache[start..end]))
+        });
+        many::hash_many(jobs.iter_mut().flatten());
+        for (hash, job) in hashes.iter_mut().zip(jobs.iter().flatten()) {
+            hash.clone_from_slice(job.to_hash().as_bytes());
+        }
+        if index < params.loop_count {
+            for (cache, hash) in caches.iter_mut().zip(hashes.iter()) {
+                cache[(start - hash_len)..start].clone_from_slice(&hash[..hash_len]);
+            }
+        }
     }
+
+    for ((output, cache), final_hash) in outputs
+        .chunks_exact_mut(params.nonce_bytes())
+        .zip(caches.iter())
+        .zip(hashes.iter())
     {
-        // generate final hash
-        let hash = blake2b(&cache[..]);
-        let hash = hash.as_bytes();
-        final_hash.clone_from_slice(&hash);
+        xor_output(params, final_hash, cache, output);
     }
-    //println!("final={:?}\nsource={:?}", final_hash, &source[..]);
+}
 
+/// XOR hash chain with final hash to output
+fn xor_output(params: &PocParams, final_hash: &[u8; 64], cache: &[u8], output: &mut [u8]) {
     // all hash_ints XOR with final_int
     // from: [hash(HASH_LENGTH)]-...-[hash0]-[addr 21bytes]-[nonce 4bytes]
     // to  : [hash'0]- ... - [hash'(HASH_LENGTH)]
+    let (loop_count, hash_len) = (params.loop_count, params.hash_len);
     for (index, item) in output.iter_mut().enumerate() {
-        let inner_pos = index % HASH_LEN; // 0~31
-        let outer_pos = index / HASH_LEN;
+        let inner_pos = index % hash_len; // 0~31
+        let outer_pos = index / hash_len;
         let x = &final_hash[inner_pos];
-        let y = &cache[(LOOP_COUNT - outer_pos - 1) * HASH_LEN + inner_pos];
+        let y = &cache[(loop_count - outer_pos - 1) * hash_len + inner_pos];
         *item = x ^ y;
         //println!("{} {:?}=={:?}^{:?}", index, item, x, y);
     }
     //println!("output={:?}", &output[..]);
 }
 
+/// fill the cache with hash chain and return final hash (first hash_len bytes)
+///
+/// all bytes hashed are written by this call, cache can be reused without clear
+pub(crate) fn hash_chain(
+    params: &PocParams,
+    version: PocVersion,
+    addr: &[u8],
+    nonce: u32,
+    cache: &mut [u8],
+) -> [u8; 64] {
+    let (hash_len, total_len) = (params.hash_len, params.total_len());
+    debug_assert_eq!(cache.len(), total_len);
+
+    // seed ..-[addr 21bytes]-[nonce 4bytes]
+    let bytes: [u8; 4] = nonce.to_le_bytes();
+    cache[(total_len - 4)..].clone_from_slice(&bytes);
+    cache[(total_len - params.seed_len())..(total_len - 4)].clone_from_slice(addr);
+    //println!("source={:?}", &source[(TOTAL_LEN-SEED_LENGTH)..]);
+
+    // seed [hash(HASH_LENGTH)]-...-[hash0]-[addr 21bytes]-[nonce 4bytes]
+    // [hashN] = blake2bp([hash(N-1)]-...-[hash0]-[addr 21bytes]-[nonce 4bytes])
+    let start_index = total_len - params.seed_len();
+    let mut hash = [0u8; 64];
+    for index in 0..(params.loop_count) {
+        let start = start_index - index * hash_len;
+        let end = min(start + 1024, total_len);
+        version.chain_hash(&cache[start..end], &mut hash[..hash_len]);
+        cache[(start - hash_len)..start].clone_from_slice(&hash[..hash_len]);
+    }
+    // generate final hash
+    let mut final_hash = [0u8; 64];
+    version.chain_hash(cache, &mut final_hash[..hash_len]);
+    //println!("final={:?}\nsource={:?}", final_hash, &source[..]);
+    final_hash
+}
+
 /// get scoped 32 bytes poc hash
+///
+/// allocates a generator cache each call, use `PocVerifier` for many blocks
 pub fn get_poc_hash(addr: &[u8], nonce: u32, time: u32, previous_hash: &[u8]) -> Vec<u8> {
-    // work = blake2b([blockTime 4bytes]-[scopeHash 32bytes]-[previousHash 32bytes])
-    assert_eq!(addr.len(), 21);
+    get_poc_hash_by_version(
+        &PocParams::MAINNET,
+        PocVersion::V1,
+        addr,
+        nonce,
+        time,
+        previous_hash,
+    )
+}
+
+/// get scoped 32 bytes poc hash of the params and version
+pub fn get_poc_hash_by_version(
+    params: &PocParams,
+    version: PocVersion,
+    addr: &[u8],
+    nonce: u32,
+    time: u32,
+    previous_hash: &[u8],
+) -> Vec<u8> {
+    // work = hash([blockTime 4bytes]-[scopeHash 32bytes]-[previousHash 32bytes])
+    assert_eq!(addr.len(), params.addr_len);
     assert_eq!(previous_hash.len(), 32);
-    let scope_hash = get_scope_hash(addr, nonce, previous_hash);
-    let mut vec = Vec::with_capacity(4 + 32 + 32);
-    let bytes: [u8; 4] = time.to_le_bytes();
-    vec.extend_from_slice(&bytes);
-    vec.extend_from_slice(&scope_hash);
-    vec.extend_from_slice(previous_hash);
-    blake2b(&vec).as_bytes()[0..32].to_vec()
+    let mut verifier = PocVerifier::with_params(*params, version);
+    verifier.work(addr, nonce, time, previous_hash).to_vec()
 }
 
 /// get scope index 0~31
 pub fn get_scope_index(previous_hash: &[u8]) -> usize {
-    // index = (previous_hash to little endian 32bytes int) % scope_length
-    assert_eq!(previous_hash.len(), 32);
-    let mut previous_hash = previous_hash.to_owned();
-    previous_hash.reverse();
-    let val: U256 = previous_hash.as_slice().into();
-    let div: U256 = (LOOP_COUNT * HASH_LEN / 32).into();
-    let index: u32 = (val % div).into();
-    index as usize
-}
-
-/// get scope_hash from full size poc hash
-fn get_scope_hash(addr: &[u8], nonce: u32, previous_hash: &[u8]) -> Vec<u8> {
-    debug_assert_eq!(addr.len(), 21);
-    debug_assert_eq!(previous_hash.len(), 32);
-    let mut output = get_generator_output();
-    let mut cache = get_generator_cache();
-    poc_generator(addr, nonce, &mut output, &mut cache);
-    let scope = get_scope_index(previous_hash);
-    let scope_hash = &output[scope * 32..scope * 32 + 32];
-    scope_hash.to_vec()
+    PocParams::MAINNET.scope_index(previous_hash)
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::allocator::*;
+    use crate::cancel::*;
+    use crate::capacity::*;
+    use crate::checksum::*;
+    use crate::error::*;
+    use crate::inplace::*;
+    use crate::lock::*;
+    use crate::mergefile::*;
+    use crate::plotfarm::*;
     use crate::plotfile::*;
+    use crate::progress::*;
+    use crate::reader::*;
     use crate::seekfile::*;
+    use crate::storage::*;
+    use crate::verifier::*;
     use crate::*;
-    use tempfile::tempdir;
+    use std::cmp::min;
+    use tempfile::{tempdir, TempDir};
 
     fn s2h(s: &str) -> Vec<u8> {
         hex::decode(s).unwrap()
     }
 
+    /// 1KiB nonces of 32 scopes, plotted in milliseconds
+    fn tiny_params() -> PocParams {
+        PocParams::new(16, 64, 21).unwrap()
+    }
+
+    /// temp dir, cancel token and 2 threads plotter config of tiny nonces
+    fn tiny_setup(chunk_nonces: usize) -> (TempDir, CancelToken, PlotterConfig) {
+        let config = PlotterConfig {
+            threads: 2,
+            chunk_nonces,
+            params: tiny_params(),
+            ..PlotterConfig::default()
+        };
+        (tempdir().unwrap(), CancelToken::new(), config)
+    }
+
     #[test]
     #[ignore]
     fn plotting() {
@@ -126,16 +457,93 @@ mod tests {
         addr.clone_from_slice(&s2h("00df64f24d74ea98b3a6734465ea9980ae9cdb2280"));
         let start = 0;
         let end = 40;
-        let unoptimized0 = plot_unoptimized_file(&addr, start, 15, tmp.path());
-        let unoptimized1 = plot_unoptimized_file(&addr, 15, end, tmp.path());
+        let config = PlotterConfig::default();
+        let cancel = CancelToken::new();
+        let unoptimized0 =
+            plot_unoptimized_file(&addr, start, 15, tmp.path(), &config, &NoProgress, &cancel)
+                .unwrap();
+        // small memory budget, only one chunk buffer
+        let config = PlotterConfig {
+            threads: 4,
+            max_memory: 0,
+            chunk_nonces: 3,
+            ..PlotterConfig::default()
+        };
+        let cancelled = CancelToken::new();
+        cancelled.cancel();
+        let result =
+            plot_unoptimized_file(&addr, 15, end, tmp.path(), &config, &NoProgress, &cancelled);
+        assert_eq!(result, Err(PocError::Cancelled));
+        let unoptimized1 =
+            plot_unoptimized_file(&addr, 15, end, tmp.path(), &config, &NoProgress, &cancel)
+                .unwrap();
 
         // check plot files restore
         let files = vec![unoptimized0, unoptimized1];
-        let restore = PlotFile::restore_from_dir(tmp.path());
+        let restore = PlotFile::restore_from_dir(tmp.path()).unwrap();
         assert_eq!(restore, files);
 
         // convert to optimized
-        let optimized = convert_to_optimized_file(files, tmp.path());
+        let config = ConverterConfig::default();
+        let optimized =
+            convert_to_optimized_file(files.clone(), tmp.path(), &config, &NoProgress, &cancel)
+                .unwrap();
+
+        // small tiles not divide nonces output same bytes
+        let tiny = tempdir().unwrap();
+        let config = ConverterConfig {
+            max_memory: 480,
+            ..ConverterConfig::default()
+        };
+        assert_eq!((config.tile_scopes(), config.tile_nonces()), (2, 7));
+        let optimized_tiny =
+            convert_to_optimized_file(files.clone(), tiny.path(), &config, &NoProgress, &cancel)
+                .unwrap();
+        let data = std::fs::read(&optimized.path).unwrap();
+        assert_eq!(data, std::fs::read(&optimized_tiny.path).unwrap());
+
+        // check transposed position
+        let unoptimized = std::fs::read(&files[1].path).unwrap();
+        for (nonce, scope) in [(15, 0), (20, 1), (39, SCOPE_COUNT - 1)].iter() {
+            let src = (nonce - 15) * LOOP_COUNT * HASH_LEN + scope * 32;
+            let dst = (scope * (end - start) + nonce) * 32;
+            assert_eq!(&unoptimized[src..src + 32], &data[dst..dst + 32]);
+        }
+
+        // split and merge again
+        let lower_dir = tempdir().unwrap();
+        let upper_dir = tempdir().unwrap();
+        let (lower, upper) = split_optimized(
+            &optimized,
+            27,
+            lower_dir.path(),
+            upper_dir.path(),
+            &config,
+            &NoProgress,
+            &cancel,
+        )
+        .unwrap();
+        assert_eq!(
+            (lower.start, lower.end, upper.start, upper.end),
+            (0, 27, 27, 40)
+        );
+        let reader = PlotReader::from_plot(&optimized).unwrap();
+        let lower_reader = PlotReader::from_plot(&lower).unwrap();
+        let upper_reader = PlotReader::from_plot(&upper).unwrap();
+        for scope in [0, 5, SCOPE_COUNT - 1].iter() {
+            let row = reader.scope_slice(*scope).unwrap();
+            assert_eq!(lower_reader.scope_slice(*scope).unwrap(), &row[..27 * 32]);
+            assert_eq!(upper_reader.scope_slice(*scope).unwrap(), &row[27 * 32..]);
+        }
+        let merged = merge_optimized(
+            vec![lower, upper],
+            tiny.path(),
+            &config,
+            &NoProgress,
+            &cancel,
+        )
+        .unwrap();
+        assert_eq!(std::fs::read(&merged.path).unwrap(), data);
 
         // calc from seek_file() by single
         let previous_hash = s2h("e34140a2ec83f237657427a98c5ab8516f75af8bc44e4c59e79e9df997df37e0");
@@ -149,6 +557,8 @@ mod tests {
             &target,
             time,
             false,
+            &NoProgress,
+            &cancel,
         )
         .unwrap();
         assert_eq!(nonce, 32);
@@ -162,16 +572,1795 @@ mod tests {
             &target,
             time,
             true,
+            &NoProgress,
+            &cancel,
         )
         .unwrap();
         assert_eq!(nonce_multi, 32);
         assert_eq!(hex::encode(work_multi), hex::encode(&work0));
 
+        // calc from unoptimized files
+        let mut works_unoptimized = vec![];
+        for plot in files.iter() {
+            match seek_plot(
+                plot,
+                &previous_hash,
+                &target,
+                time,
+                SeekMode::All,
+                true,
+                &NoProgress,
+                &cancel,
+            ) {
+                Ok(works) => works_unoptimized.extend(works),
+                Err(err) => assert_eq!(err, PocError::NotFound),
+            }
+        }
+        works_unoptimized.sort_by(|a, b| a.1.iter().rev().cmp(b.1.iter().rev()));
+
+        // calc from PlotFarm
+        let farm = PlotFarm::load(&addr, &[tmp.path()]).unwrap();
+        assert_eq!(farm.nonces(), end - start);
+        let works_farm = farm
+            .seek(
+                PocVersion::V1,
+                &previous_hash,
+                &target,
+                time,
+                SeekMode::All,
+                &NoProgress,
+                &cancel,
+            )
+            .unwrap();
+        assert!(works_farm.iter().any(|(nonce, _)| *nonce == 32));
+        assert_eq!(works_farm, works_unoptimized);
+
+        // best and top-k are the lowest works of all
+        let (nonce_best, work_best) = farm
+            .seek(
+                PocVersion::V1,
+                &previous_hash,
+                &target,
+                time,
+                SeekMode::Best,
+                &NoProgress,
+                &cancel,
+            )
+            .unwrap()
+            .remove(0);
+        assert_eq!((nonce_best, work_best), works_farm[0].clone());
+        for multi in [false, true].iter() {
+            let works_top = seek_file_by_mode(
+                &optimized.path,
+                start,
+                end,
+                &previous_hash,
+                &target,
+                time,
+                SeekMode::TopK(2),
+                *multi,
+                &NoProgress,
+                &cancel,
+            )
+            .unwrap();
+            assert_eq!(works_top, works_farm[..min(2, works_farm.len())].to_vec());
+        }
+
+        // calc from seek_time_window(), no work before the time
+        let (time_window, nonce_window, work_window) = seek_time_window(
+            &optimized,
+            &previous_hash,
+            &target,
+            time - 10..time + 10,
+            &NoProgress,
+            &cancel,
+        )
+        .unwrap();
+        assert!(time_window <= time);
+        let work_expect = get_poc_hash(&addr, nonce_window, time_window, &previous_hash);
+        assert_eq!(hex::encode(&work_window), hex::encode(work_expect));
+        for past in time - 10..time_window {
+            let result = seek_file(
+                &optimized.path,
+                start,
+                end,
+                &previous_hash,
+                &target,
+                past,
+                true,
+                &NoProgress,
+                &cancel,
+            );
+            assert_eq!(result, Err(PocError::NotFound));
+        }
+
         // calc from get_poc_hash()
         let work1 = get_poc_hash(&addr, nonce, time, &previous_hash);
         assert_eq!(hex::encode(&work0), hex::encode(&work1));
     }
 
+    #[test]
+    fn tiny_plotting() {
+        let (tmp, cancel, config) = tiny_setup(7);
+        let params = config.params;
+        assert_eq!((params.nonce_bytes(), params.scope_count()), (1024, 32));
+        assert!(PocParams::new(3, 10, 21).is_err());
+        let addr = [5u8; 21];
+        let config = PlotterConfig {
+            threads: 3,
+            max_memory: 0,
+            ..config
+        };
+        let files = vec![
+            plot_unoptimized_file(&addr, 0, 20, tmp.path(), &config, &NoProgress, &cancel).unwrap(),
+            plot_unoptimized_file(&addr, 20, 50, tmp.path(), &config, &NoProgress, &cancel)
+                .unwrap(),
+        ];
+        let scan = PlotFile::scan_dir(tmp.path(), &params).unwrap();
+        assert_eq!(scan.plots, files);
+
+        // each nonce is same as generator output
+        let unoptimized = std::fs::read(&files[1].path).unwrap();
+        let mut output = params.generator_output();
+        let mut cache = params.generator_cache();
+        poc_generator_by_version(&params, PocVersion::V1, &addr, 23, &mut output, &mut cache);
+        assert_eq!(&unoptimized[3 * 1024..4 * 1024], output.as_ref());
+
+        // converted by small tiles, then split and merged back
+        let out = tempdir().unwrap();
+        let config = ConverterConfig {
+            max_memory: 480,
+            ..ConverterConfig::default()
+        };
+        let optimized =
+            convert_to_optimized_file(files.clone(), out.path(), &config, &NoProgress, &cancel)
+                .unwrap();
+        let data = std::fs::read(&optimized.path).unwrap();
+        assert_eq!(data.len(), 50 * 1024);
+        for scope in [0, 5, 31].iter() {
+            let dst = (scope * 50 + 23) * 32;
+            assert_eq!(&data[dst..dst + 32], &output[scope * 32..scope * 32 + 32]);
+        }
+        let halves = tempdir().unwrap();
+        let (lower, upper) = split_optimized(
+            &optimized,
+            13,
+            halves.path(),
+            halves.path(),
+            &config,
+            &NoProgress,
+            &cancel,
+        )
+        .unwrap();
+        let merged = tempdir().unwrap();
+        let merged = merge_optimized(
+            vec![lower, upper],
+            merged.path(),
+            &config,
+            &NoProgress,
+            &cancel,
+        )
+        .unwrap();
+        assert_eq!(std::fs::read(&merged.path).unwrap(), data);
+
+        // both layouts read back the generator output
+        let mut verifier = PocVerifier::with_params(params, PocVersion::V1);
+        for plot in [&files[1], &optimized].iter() {
+            let reader = PlotReader::from_plot(plot).unwrap();
+            assert_eq!(reader.read_nonce(23).unwrap().as_ref(), output.as_ref());
+            let scope_hash = verifier.scope_hash(&addr, 23, 17);
+            assert_eq!(reader.read_scope(23, 17).unwrap(), scope_hash);
+        }
+
+        // all layouts, farm and verifier find same works
+        let previous_hash = [9u8; 32];
+        let mut target = [0xffu8; 32];
+        target[31] = 0x40;
+        let time = 100;
+        let seek = |plot: &PlotFile| {
+            seek_plot(
+                plot,
+                &previous_hash,
+                &target,
+                time,
+                SeekMode::All,
+                true,
+                &NoProgress,
+                &cancel,
+            )
+            .unwrap()
+        };
+        let mut works = seek(&files[0]);
+        works.extend(seek(&files[1]));
+        works.sort_by(|a, b| a.1.iter().rev().cmp(b.1.iter().rev()));
+        assert!(!works.is_empty() && works.len() < 50);
+        assert_eq!(seek(&optimized), works);
+        let farm = PlotFarm::load_with_params(&addr, &[out.path()], &params).unwrap();
+        let works_farm = farm
+            .seek(
+                PocVersion::V1,
+                &previous_hash,
+                &target,
+                time,
+                SeekMode::All,
+                &NoProgress,
+                &cancel,
+            )
+            .unwrap();
+        assert_eq!(works_farm, works);
+        let mut verifier = PocVerifier::with_params(params, PocVersion::V1);
+        for (nonce, work) in works.iter() {
+            let verified = verifier.verify(&addr, *nonce, time, &previous_hash, &target);
+            assert_eq!(&verified.unwrap()[..], &work[..]);
+        }
+
+        // window finds the earliest time
+        let (time_window, nonce_window, work_window) = seek_time_window(
+            &optimized,
+            &previous_hash,
+            &target,
+            time - 5..time + 5,
+            &NoProgress,
+            &cancel,
+        )
+        .unwrap();
+        assert!(time_window <= time);
+        let work = verifier.work(&addr, nonce_window, time_window, &previous_hash);
+        assert_eq!(&work[..], &work_window[..]);
+
+        // mainnet scan rejects tiny plots by size
+        let scan = PlotFile::scan_dir(tmp.path(), &PocParams::MAINNET).unwrap();
+        assert!(scan.plots.is_empty());
+        assert_eq!(scan.rejected.len(), 2);
+
+        // other address length is plotted, scanned and verified
+        let params = PocParams::new(16, 64, 20).unwrap();
+        let addr = [6u8; 20];
+        let short = tempdir().unwrap();
+        let config = PlotterConfig {
+            chunk_nonces: 3,
+            params,
+            ..PlotterConfig::default()
+        };
+        let plot = plot_unoptimized_file(&addr, 0, 8, short.path(), &config, &NoProgress, &cancel)
+            .unwrap();
+        assert_eq!(plot.addr, addr.to_vec());
+        assert_eq!(
+            PlotFile::scan_dir(short.path(), &params).unwrap().plots,
+            vec![plot.clone()]
+        );
+        let scan = PlotFile::scan_dir(short.path(), &files[0].params).unwrap();
+        assert_eq!(scan.rejected[0].reason, "address is 20 bytes, not 21");
+        let mut verifier = PocVerifier::with_params(params, PocVersion::V1);
+        let reader = PlotReader::from_plot(&plot).unwrap();
+        assert_eq!(
+            reader.read_scope(5, 9).unwrap(),
+            verifier.scope_hash(&addr, 5, 9)
+        );
+        assert!(plot_unoptimized_file(
+            &[6u8; 21],
+            8,
+            9,
+            short.path(),
+            &config,
+            &NoProgress,
+            &cancel
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn seek_modes() {
+        let (tmp, cancel, config) = tiny_setup(8);
+        let addr = [9u8; 21];
+        let plot =
+            plot_unoptimized_file(&addr, 0, 40, tmp.path(), &config, &NoProgress, &cancel).unwrap();
+        let out = tempdir().unwrap();
+        let converter = ConverterConfig::default();
+        let optimized = convert_to_optimized_file(
+            vec![plot.clone()],
+            out.path(),
+            &converter,
+            &NoProgress,
+            &cancel,
+        )
+        .unwrap();
+        let previous_hash = [4u8; 32];
+        let mut target = [0xffu8; 32];
+        target[31] = 0x80;
+        let seek = |plot: &PlotFile, mode: SeekMode, multi: bool| {
+            seek_plot(
+                plot,
+                &previous_hash,
+                &target,
+                100,
+                mode,
+                multi,
+                &NoProgress,
+                &cancel,
+            )
+        };
+
+        // all hits ordered from the lowest work
+        let all = seek(&optimized, SeekMode::All, false).unwrap();
+        assert!(3 < all.len() && all.len() < 40);
+        let mut sorted = all.clone();
+        sorted.sort_by(|a, b| a.1.iter().rev().cmp(b.1.iter().rev()));
+        assert_eq!(all, sorted);
+
+        // bounded modes are heads of all hits, same by threads and layouts
+        for multi in [false, true].iter() {
+            for file in [&optimized, &plot].iter() {
+                assert_eq!(seek(file, SeekMode::All, *multi).unwrap(), all);
+                assert_eq!(seek(file, SeekMode::Best, *multi).unwrap(), all[..1]);
+                assert_eq!(seek(file, SeekMode::TopK(3), *multi).unwrap(), all[..3]);
+                let first = seek(file, SeekMode::First, *multi).unwrap();
+                assert_eq!(first.len(), 1);
+                assert!(all.contains(&first[0]));
+            }
+        }
+        let top = seek_reader(
+            &PlotReader::from_plot(&optimized).unwrap(),
+            &previous_hash,
+            &target,
+            100,
+            SeekMode::TopK(2),
+            true,
+            &NoProgress,
+            &cancel,
+        )
+        .unwrap();
+        assert_eq!(top, all[..2]);
+        let mut verifier = PocVerifier::with_params(config.params, PocVersion::V1);
+        for (nonce, work) in all.iter() {
+            assert_eq!(
+                verifier.work(&addr, *nonce, 100, &previous_hash).to_vec(),
+                *work
+            );
+        }
+
+        // no work under zero target
+        let result = seek_plot(
+            &plot,
+            &previous_hash,
+            &[0u8; 32],
+            100,
+            SeekMode::All,
+            true,
+            &NoProgress,
+            &cancel,
+        );
+        assert_eq!(result, Err(PocError::NotFound));
+    }
+
+    #[test]
+    fn time_window() {
+        let (tmp, cancel, config) = tiny_setup(8);
+        let addr = [10u8; 21];
+        let plot =
+            plot_unoptimized_file(&addr, 0, 40, tmp.path(), &config, &NoProgress, &cancel).unwrap();
+        let out = tempdir().unwrap();
+        let converter = ConverterConfig::default();
+        let optimized =
+            convert_to_optimized_file(vec![plot], out.path(), &converter, &NoProgress, &cancel)
+                .unwrap();
+        let previous_hash = [5u8; 32];
+        let mut target = [0xffu8; 32];
+        target[31] = 0x04;
+        let seek = |time: u32, mode: SeekMode| {
+            seek_plot(
+                &optimized,
+                &previous_hash,
+                &target,
+                time,
+                mode,
+                false,
+                &NoProgress,
+                &cancel,
+            )
+        };
+
+        // the earliest time and the lowest work at the time
+        let (time, nonce, work) = seek_time_window(
+            &optimized,
+            &previous_hash,
+            &target,
+            100..140,
+            &NoProgress,
+            &cancel,
+        )
+        .unwrap();
+        assert!((100..140).contains(&time));
+        for past in 100..time {
+            assert_eq!(seek(past, SeekMode::First), Err(PocError::NotFound));
+        }
+        assert_eq!(seek(time, SeekMode::Best).unwrap(), vec![(nonce, work)]);
+
+        // window of no work, and cancelled before read
+        let result = seek_time_window(
+            &optimized,
+            &previous_hash,
+            &[0u8; 32],
+            100..102,
+            &NoProgress,
+            &cancel,
+        );
+        assert_eq!(result, Err(PocError::NotFound));
+        let cancelled = CancelToken::new();
+        cancelled.cancel();
+        let result = seek_time_window(
+            &optimized,
+            &previous_hash,
+            &target,
+            100..102,
+            &NoProgress,
+            &cancelled,
+        );
+        assert_eq!(result, Err(PocError::Cancelled));
+    }
+
+    #[test]
+    fn capacity() {
+        let params = PocParams::MAINNET;
+        assert_eq!(plot_bytes(&params, 2), 1024 * 1024);
+        assert_eq!(nonces_in_bytes(&params, 14 * 1000u64.pow(4)), 26702880);
+        assert_eq!(range_in_bytes(&params, 100, 1024 * 1024 + 1), 100..102);
+        assert_eq!(convert_bytes(&params, 3), plot_bytes(&params, 3));
+
+        // free space of a real disk
+        let tmp = tempdir().unwrap();
+        let free = free_space(tmp.path()).unwrap();
+        assert!(0 < free);
+        let nonces = nonces_in_free_space(&params, tmp.path(), 0).unwrap();
+        assert_eq!(nonces, nonces_in_bytes(&params, free));
+        assert_eq!(nonces_in_free_space(&params, tmp.path(), u64::MAX), Ok(0));
+
+        // too large range fails before writing
+        let config = PlotterConfig::default();
+        let cancel = CancelToken::new();
+        let end = nonces + 1;
+        let result = plot_unoptimized_file(
+            &[0u8; 21],
+            0,
+            end,
+            tmp.path(),
+            &config,
+            &NoProgress,
+            &cancel,
+        );
+        assert!(matches!(result, Err(PocError::NoSpace { .. })));
+        assert_eq!(std::fs::read_dir(tmp.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn allocator() {
+        let params = tiny_params();
+        let addr = [1u8; 21];
+        let disk0 = tempdir().unwrap();
+        let disk1 = tempdir().unwrap();
+        for (dir, start, end) in [(&disk0, 0, 10), (&disk1, 20, 30)].iter() {
+            let name = format!("optimized.{}-{}-{}.dat", hex::encode(addr), start, end);
+            let fs = std::fs::File::create(dir.path().join(name)).unwrap();
+            fs.set_len(((end - start) * params.nonce_bytes()) as u64)
+                .unwrap();
+        }
+        let path = disk0.path().join("reserved.txt");
+        let mut allocator = RangeAllocator::new(&addr, &[disk0.path(), disk1.path()], &path);
+        allocator.params = params;
+        assert_eq!(allocator.allocate(5), Ok(10..15));
+        assert_eq!(allocator.allocate(5), Ok(15..20));
+        assert_eq!(allocator.allocate(3), Ok(30..33));
+
+        // released range is reused, reservations persist across instances
+        allocator.release(15..20).unwrap();
+        assert_eq!(allocator.allocate(6), Ok(33..39));
+        let other = allocator.clone();
+        assert_eq!(other.reserved(), Ok(vec![10..15, 30..33, 33..39]));
+        assert_eq!(other.allocate(5), Ok(15..20));
+
+        // parallel allocators never collide
+        let mut ranges = std::thread::scope(|s| {
+            let handles = (0..4)
+                .map(|_| {
+                    let allocator = allocator.clone();
+                    s.spawn(move || allocator.allocate(2).unwrap())
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        ranges.sort_by_key(|range| range.start);
+        assert_eq!(ranges, vec![39..41, 41..43, 43..45, 45..47]);
+    }
+
+    #[test]
+    fn pipeline() {
+        #[derive(Default)]
+        struct Recorder {
+            nonces: std::sync::Mutex<usize>,
+            rates: std::sync::Mutex<Vec<StageRates>>,
+        }
+        impl ProgressSink for Recorder {
+            fn chunk_done(&self, progress: &Progress) {
+                *self.nonces.lock().unwrap() += progress.chunk_nonces;
+            }
+            fn stage_rates(&self, rates: &StageRates) {
+                self.rates.lock().unwrap().push(rates.clone());
+            }
+        }
+
+        // one nonce chunks on 3 buffers finish out of order, written in order
+        let (tmp, cancel, config) = tiny_setup(1);
+        let addr = [4u8; 21];
+        let config = PlotterConfig {
+            threads: 4,
+            max_memory: 3 * 1024,
+            ..config
+        };
+        let recorder = Recorder::default();
+        let plot =
+            plot_unoptimized_file(&addr, 10, 70, tmp.path(), &config, &recorder, &cancel).unwrap();
+        assert_eq!(*recorder.nonces.lock().unwrap(), 60);
+        let rates = recorder.rates.lock().unwrap();
+        assert!(!rates.is_empty());
+        let last = rates.last().unwrap();
+        assert!(0.0 < last.hashing && 0.0 < last.writing);
+
+        // same as single thread plotting
+        let single = tempdir().unwrap();
+        let config = PlotterConfig {
+            threads: 1,
+            chunk_nonces: 7,
+            params: tiny_params(),
+            ..PlotterConfig::default()
+        };
+        let expect =
+            plot_unoptimized_file(&addr, 10, 70, single.path(), &config, &NoProgress, &cancel)
+                .unwrap();
+        assert_eq!(
+            std::fs::read(&plot.path).unwrap(),
+            std::fs::read(&expect.path).unwrap()
+        );
+    }
+
+    #[test]
+    fn striped() {
+        let (_, cancel, config) = tiny_setup(4);
+        let addr = [6u8; 21];
+        let disks = [tempdir().unwrap(), tempdir().unwrap(), tempdir().unwrap()];
+        let dirs = disks.iter().map(|disk| disk.path()).collect::<Vec<_>>();
+        let config = PlotterConfig {
+            threads: 3,
+            ..config
+        };
+        let result = plot_unoptimized_files(&addr, 0, 2, &dirs, &config, &NoProgress, &cancel);
+        assert!(matches!(result, Err(PocError::Invalid(_))));
+
+        // cancelled files resume by same arguments
+        let cancelled = CancelToken::new();
+        cancelled.cancel();
+        let result = plot_unoptimized_files(&addr, 10, 71, &dirs, &config, &NoProgress, &cancelled);
+        assert_eq!(result, Err(PocError::Cancelled));
+        let plots =
+            plot_unoptimized_files(&addr, 10, 71, &dirs, &config, &NoProgress, &cancel).unwrap();
+        let ranges = plots
+            .iter()
+            .map(|plot| (plot.start, plot.end))
+            .collect::<Vec<_>>();
+        assert_eq!(ranges, vec![(10, 30), (30, 50), (50, 71)]);
+
+        // each disk holds its part of one plot
+        let single = tempdir().unwrap();
+        let expect =
+            plot_unoptimized_file(&addr, 10, 71, single.path(), &config, &NoProgress, &cancel)
+                .unwrap();
+        let expect = std::fs::read(&expect.path).unwrap();
+        for (plot, dir) in plots.iter().zip(dirs.iter()) {
+            assert_eq!(plot.path.parent(), Some(*dir));
+            let first = (plot.start - 10) * 1024;
+            let data = std::fs::read(&plot.path).unwrap();
+            assert_eq!(data, &expect[first..first + data.len()]);
+        }
+    }
+
+    #[test]
+    fn in_place() {
+        struct CancelAfterSegment(CancelToken);
+        impl ProgressSink for CancelAfterSegment {
+            fn finished(&self, _elapsed: std::time::Duration) {
+                self.0.cancel();
+            }
+        }
+
+        let (disk, cancel, config) = tiny_setup(8);
+        let params = config.params;
+        let addr = [7u8; 21];
+        let plot = plot_unoptimized_file(&addr, 0, 50, disk.path(), &config, &NoProgress, &cancel)
+            .unwrap();
+        let copy = tempdir().unwrap();
+        let converter = ConverterConfig {
+            max_memory: 2000,
+            ..ConverterConfig::default()
+        };
+        let expect = convert_to_optimized_file(
+            vec![plot.clone()],
+            copy.path(),
+            &converter,
+            &NoProgress,
+            &cancel,
+        )
+        .unwrap();
+
+        // killed after the first segment, the file is cut and hidden from farms
+        let cancelled = CancelToken::new();
+        let sink = CancelAfterSegment(cancelled.clone());
+        let result = optimize_in_place(&plot, 16, &converter, &sink, &cancelled);
+        assert_eq!(result, Err(PocError::Cancelled));
+        assert!(!plot.path.exists());
+        let work = plot.path.with_extension("inplace");
+        assert_eq!(std::fs::metadata(&work).unwrap().len(), 34 * 1024);
+        let scan = PlotFile::scan_dir(disk.path(), &params).unwrap();
+        let ranges = scan
+            .plots
+            .iter()
+            .map(|plot| (plot.flag.clone(), plot.start, plot.end))
+            .collect::<Vec<_>>();
+        assert_eq!(ranges, vec![(PlotFlag::Optimized, 34, 50)]);
+        assert_eq!(scan.pending, vec![plot.clone()]);
+
+        // journal survives cleaning of a dead plotter of the same range
+        let journal = plot.path.with_extension("inplace.resume");
+        assert_eq!(read_journal(&journal), vec![(34, 50)]);
+        std::fs::write(plot.path.with_extension("tmp"), b"").unwrap();
+        std::fs::write(plot.path.with_extension("resume"), "0-8\n").unwrap();
+        let removed = clean_stale_tmp(disk.path()).unwrap();
+        assert!(removed.contains(&plot.path.with_extension("resume")));
+        assert!(journal.exists());
+
+        // resumed by same arguments, segments merge to the copy conversion
+        let pending = &scan.pending[0];
+        let segments = optimize_in_place(pending, 16, &converter, &NoProgress, &cancel).unwrap();
+        let ranges = segments
+            .iter()
+            .map(|plot| (plot.start, plot.end))
+            .collect::<Vec<_>>();
+        assert_eq!(ranges, vec![(0, 2), (2, 18), (18, 34), (34, 50)]);
+        assert!(!work.exists());
+        assert!(!journal.exists());
+        let scan = PlotFile::scan_dir(disk.path(), &params).unwrap();
+        assert_eq!((scan.plots, scan.pending), (segments.clone(), vec![]));
+        let merged = tempdir().unwrap();
+        let merged =
+            merge_optimized(segments, merged.path(), &converter, &NoProgress, &cancel).unwrap();
+        assert_eq!(
+            std::fs::read(&merged.path).unwrap(),
+            std::fs::read(&expect.path).unwrap()
+        );
+    }
+
+    #[test]
+    fn write_options() {
+        // same bytes with or without preallocation and direct io
+        let (_, cancel, config) = tiny_setup(5);
+        let addr = [3u8; 21];
+        let outputs = [(false, false), (true, false), (true, true)]
+            .iter()
+            .map(|&(preallocate, direct_io)| {
+                let (tmp, out, merged) =
+                    (tempdir().unwrap(), tempdir().unwrap(), tempdir().unwrap());
+                let config = PlotterConfig {
+                    preallocate,
+                    direct_io,
+                    ..config.clone()
+                };
+                let plot =
+                    plot_unoptimized_file(&addr, 3, 40, tmp.path(), &config, &NoProgress, &cancel)
+                        .unwrap();
+                let config = ConverterConfig {
+                    max_memory: 3000,
+                    preallocate,
+                    direct_io,
+                    ..ConverterConfig::default()
+                };
+                let optimized = convert_to_optimized_file(
+                    vec![plot.clone()],
+                    out.path(),
+                    &config,
+                    &NoProgress,
+                    &cancel,
+                )
+                .unwrap();
+                let (lower, upper) = split_optimized(
+                    &optimized,
+                    20,
+                    tmp.path(),
+                    tmp.path(),
+                    &config,
+                    &NoProgress,
+                    &cancel,
+                )
+                .unwrap();
+                let merged = merge_optimized(
+                    vec![lower, upper],
+                    merged.path(),
+                    &config,
+                    &NoProgress,
+                    &cancel,
+                )
+                .unwrap();
+                let plotted = std::fs::read(&plot.path).unwrap();
+                let optimized = std::fs::read(&optimized.path).unwrap();
+                assert_eq!(plotted.len(), 37 * 1024);
+                assert_eq!(std::fs::read(&merged.path).unwrap(), optimized);
+                (plotted, optimized)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(outputs[0], outputs[1]);
+        assert_eq!(outputs[0], outputs[2]);
+    }
+
+    #[test]
+    fn locking() {
+        let (tmp, cancel, config) = tiny_setup(PlotterConfig::default().chunk_nonces);
+        let addr = [2u8; 21];
+
+        // other plotter of the range holds the tmp file, it is not broken
+        let name = format!("unoptimized.{}-0-8", hex::encode(addr));
+        let tmp_path = tmp.path().join(format!("{}.tmp", name));
+        std::fs::write(&tmp_path, [7u8; 100]).unwrap();
+        let holder = std::fs::File::open(&tmp_path).unwrap();
+        fs2::FileExt::try_lock_exclusive(&holder).unwrap();
+        let result = plot_unoptimized_file(&addr, 0, 8, tmp.path(), &config, &NoProgress, &cancel);
+        assert!(matches!(result, Err(PocError::Locked(_))));
+        assert_eq!(std::fs::read(&tmp_path).unwrap(), vec![7u8; 100]);
+        assert!(is_locked(&tmp_path).unwrap());
+        assert_eq!(clean_stale_tmp(tmp.path()), Ok(vec![]));
+
+        // dead holder's tmp and journal are removed
+        std::fs::write(tmp.path().join(format!("{}.resume", name)), "0-4\n").unwrap();
+        drop(holder);
+        assert!(!is_locked(&tmp_path).unwrap());
+        assert_eq!(clean_stale_tmp(tmp.path()).unwrap().len(), 2);
+        assert!(!tmp_path.exists());
+
+        // readers share the plot, converting is fine while seeking
+        let plot =
+            plot_unoptimized_file(&addr, 0, 8, tmp.path(), &config, &NoProgress, &cancel).unwrap();
+        let reader = PlotReader::from_plot(&plot).unwrap();
+        let other = PlotReader::from_plot(&plot).unwrap();
+        assert!(is_locked(&plot.path).unwrap());
+        let out = tempdir().unwrap();
+        let converter = ConverterConfig::default();
+        let optimized =
+            convert_to_optimized_file(vec![plot], out.path(), &converter, &NoProgress, &cancel)
+                .unwrap();
+        drop((reader, other));
+
+        // writer of the plot file blocks readers
+        let holder = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&optimized.path)
+            .unwrap();
+        fs2::FileExt::try_lock_exclusive(&holder).unwrap();
+        assert!(matches!(
+            PlotReader::from_plot(&optimized),
+            Err(PocError::Locked(_))
+        ));
+        let halves = tempdir().unwrap();
+        let result = split_optimized(
+            &optimized,
+            4,
+            halves.path(),
+            halves.path(),
+            &converter,
+            &NoProgress,
+            &cancel,
+        );
+        assert!(matches!(result, Err(PocError::Locked(_))));
+    }
+
+    #[test]
+    fn cancelled() {
+        let addr = [0u8; 21];
+        let tmp = tempdir().unwrap();
+        let cancel = CancelToken::new();
+        cancel.cancel();
+
+        // plotting leaves tmp file and journal
+        let config = PlotterConfig::default();
+        let result =
+            plot_unoptimized_file(&addr, 0, 100, tmp.path(), &config, &NoProgress, &cancel);
+        assert_eq!(result, Err(PocError::Cancelled));
+        let name = format!("unoptimized.{}-0-100", hex::encode(addr));
+        assert!(tmp.path().join(format!("{}.tmp", name)).exists());
+        assert!(tmp.path().join(format!("{}.resume", name)).exists());
+
+        // seeking stops before read
+        let path = tmp.path().join(format!("{}.tmp", name));
+        let previous_hash = [0u8; 32];
+        let target = [255u8; 32];
+        for multi in [false, true].iter() {
+            let result = seek_file(
+                &path,
+                0,
+                100,
+                &previous_hash,
+                &target,
+                0,
+                *multi,
+                &NoProgress,
+                &cancel,
+            );
+            assert_eq!(result, Err(PocError::Cancelled));
+        }
+    }
+
+    #[test]
+    fn resumed() {
+        struct CancelAfterChunks(CancelToken, std::sync::atomic::AtomicUsize);
+        impl ProgressSink for CancelAfterChunks {
+            fn chunk_done(&self, _progress: &Progress) {
+                use std::sync::atomic::Ordering;
+                if self.1.fetch_sub(1, Ordering::SeqCst) == 1 {
+                    self.0.cancel();
+                }
+            }
+        }
+        let cancel_after = |chunks: usize| {
+            let cancel = CancelToken::new();
+            let sink = CancelAfterChunks(cancel.clone(), chunks.into());
+            (cancel, sink)
+        };
+
+        let (expect, cancel, config) = tiny_setup(4);
+        let addr = [2u8; 21];
+        let config = PlotterConfig {
+            max_memory: 0,
+            ..config
+        };
+        let expect =
+            plot_unoptimized_file(&addr, 0, 60, expect.path(), &config, &NoProgress, &cancel)
+                .unwrap();
+
+        // plotting cancelled in the middle resumes to same bytes
+        let tmp = tempdir().unwrap();
+        let (cancelled, sink) = cancel_after(3);
+        let result = plot_unoptimized_file(&addr, 0, 60, tmp.path(), &config, &sink, &cancelled);
+        assert_eq!(result, Err(PocError::Cancelled));
+        let name = format!("unoptimized.{}-0-60", hex::encode(addr));
+        let finished = read_journal(&tmp.path().join(format!("{}.resume", name)));
+        assert!(3 <= finished.len() && finished.len() < 15);
+
+        // preallocated tmp file holds the plot size, resuming asks no more space
+        let path = tmp.path().join(format!("{}.tmp", name));
+        let held = std::fs::File::open(&path).unwrap();
+        assert_eq!(needed_space(&held, 60 * 1024).unwrap(), 0);
+        std::mem::drop(held);
+        let sparse = tempdir().unwrap();
+        let fresh = std::fs::File::create(sparse.path().join("fresh.tmp")).unwrap();
+        assert_eq!(needed_space(&fresh, 60 * 1024).unwrap(), 60 * 1024);
+        let other = PlotterConfig {
+            checksum_nonces: Some(4),
+            ..config.clone()
+        };
+        let result = plot_unoptimized_file(&addr, 0, 60, tmp.path(), &other, &NoProgress, &cancel);
+        assert!(matches!(result, Err(PocError::Invalid(_))));
+
+        // longer tmp file is cut to the plot size
+        let fs = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        fs.set_len(70 * 1024).unwrap();
+        std::mem::drop(fs);
+        let plot =
+            plot_unoptimized_file(&addr, 0, 60, tmp.path(), &config, &NoProgress, &cancel).unwrap();
+        assert_eq!(
+            std::fs::read(&plot.path).unwrap(),
+            std::fs::read(&expect.path).unwrap()
+        );
+
+        // converting cancelled in the second band resumes to same bytes
+        let converter = ConverterConfig {
+            max_memory: 480,
+            ..ConverterConfig::default()
+        };
+        let copy = tempdir().unwrap();
+        let expect = convert_to_optimized_file(
+            vec![plot.clone()],
+            copy.path(),
+            &converter,
+            &NoProgress,
+            &cancel,
+        )
+        .unwrap();
+        let out = tempdir().unwrap();
+        let (cancelled, sink) = cancel_after(12);
+        let result = convert_to_optimized_file(
+            vec![plot.clone()],
+            out.path(),
+            &converter,
+            &sink,
+            &cancelled,
+        );
+        assert_eq!(result, Err(PocError::Cancelled));
+        let name = format!("optimized.{}-0-60", hex::encode(addr));
+        let finished = read_journal(&out.path().join(format!("{}.resume", name)));
+        assert_eq!(finished, vec![(0, 2)]);
+        let other = ConverterConfig {
+            checksum_nonces: Some(4),
+            ..converter.clone()
+        };
+        let result =
+            convert_to_optimized_file(vec![plot.clone()], out.path(), &other, &NoProgress, &cancel);
+        assert!(matches!(result, Err(PocError::Invalid(_))));
+        let optimized =
+            convert_to_optimized_file(vec![plot], out.path(), &converter, &NoProgress, &cancel)
+                .unwrap();
+        assert_eq!(
+            std::fs::read(&optimized.path).unwrap(),
+            std::fs::read(&expect.path).unwrap()
+        );
+    }
+
+    #[test]
+    fn converter() {
+        let (tmp, cancel, config) = tiny_setup(5);
+        let addr = [4u8; 21];
+        let files = vec![
+            plot_unoptimized_file(&addr, 0, 13, tmp.path(), &config, &NoProgress, &cancel).unwrap(),
+            plot_unoptimized_file(&addr, 13, 40, tmp.path(), &config, &NoProgress, &cancel)
+                .unwrap(),
+        ];
+
+        // transposed scope by scope
+        let mut unoptimized = std::fs::read(&files[0].path).unwrap();
+        unoptimized.extend(std::fs::read(&files[1].path).unwrap());
+        let mut expect = vec![];
+        for scope in 0..32 {
+            for nonce in 0..40 {
+                let pos = nonce * 1024 + scope * 32;
+                expect.extend_from_slice(&unoptimized[pos..pos + 32]);
+            }
+        }
+
+        // tiles of one hash, uneven tiles across the files and one tile of all
+        for max_memory in [0, 32, 100, 480, 3000, 1 << 20].iter() {
+            let converter = ConverterConfig {
+                max_memory: *max_memory,
+                ..ConverterConfig::default()
+            };
+            let out = tempdir().unwrap();
+            let optimized = convert_to_optimized_file(
+                files.clone(),
+                out.path(),
+                &converter,
+                &NoProgress,
+                &cancel,
+            )
+            .unwrap();
+            assert_eq!((optimized.start, optimized.end), (0, 40));
+            assert_eq!(std::fs::read(&optimized.path).unwrap(), expect);
+        }
+    }
+
+    #[test]
+    fn merge_split() {
+        let (tmp, cancel, config) = tiny_setup(8);
+        let addr = [5u8; 21];
+        let plot = plot_unoptimized_file(&addr, 10, 40, tmp.path(), &config, &NoProgress, &cancel)
+            .unwrap();
+        let converter = ConverterConfig {
+            max_memory: 3000,
+            ..ConverterConfig::default()
+        };
+        let out = tempdir().unwrap();
+        let optimized = convert_to_optimized_file(
+            vec![plot.clone()],
+            out.path(),
+            &converter,
+            &NoProgress,
+            &cancel,
+        )
+        .unwrap();
+        let data = std::fs::read(&optimized.path).unwrap();
+
+        // each half holds its nonces of every scope row
+        let halves = tempdir().unwrap();
+        let split = |file: &PlotFile, at: usize| {
+            let dir = halves.path();
+            split_optimized(file, at, dir, dir, &converter, &NoProgress, &cancel).unwrap()
+        };
+        let (lower, rest) = split(&optimized, 11);
+        let (middle, upper) = split(&rest, 39);
+        for (file, start, end) in [(&lower, 10, 11), (&middle, 11, 39), (&upper, 39, 40)].iter() {
+            assert_eq!((file.start, file.end), (*start, *end));
+            let half = std::fs::read(&file.path).unwrap();
+            for scope in [0, 7, 31].iter() {
+                let row = &data[scope * 30 * 32..(scope + 1) * 30 * 32];
+                let nonces = end - start;
+                assert_eq!(
+                    &half[scope * nonces * 32..(scope + 1) * nonces * 32],
+                    &row[(start - 10) * 32..(end - 10) * 32]
+                );
+            }
+        }
+
+        // three files merged back
+        let merged = tempdir().unwrap();
+        let files = vec![lower.clone(), middle.clone(), upper.clone()];
+        let merged =
+            merge_optimized(files, merged.path(), &converter, &NoProgress, &cancel).unwrap();
+        assert_eq!((merged.start, merged.end), (10, 40));
+        assert_eq!(std::fs::read(&merged.path).unwrap(), data);
+
+        // wrong inputs
+        let dir = tempdir().unwrap();
+        let invalid =
+            |result: Result<PlotFile, PocError>| matches!(result, Err(PocError::Invalid(_)));
+        let merge = |files: Vec<PlotFile>| {
+            merge_optimized(files, dir.path(), &converter, &NoProgress, &cancel)
+        };
+        assert!(invalid(merge(vec![lower.clone()])));
+        assert!(invalid(merge(vec![lower.clone(), upper.clone()])));
+        assert!(invalid(merge(vec![plot.clone(), rest.clone()])));
+        for at in [10, 40].iter() {
+            let result = split_optimized(
+                &optimized,
+                *at,
+                dir.path(),
+                dir.path(),
+                &converter,
+                &NoProgress,
+                &cancel,
+            );
+            assert!(matches!(result, Err(PocError::Invalid(_))));
+        }
+        let result = split_optimized(
+            &plot,
+            20,
+            dir.path(),
+            dir.path(),
+            &converter,
+            &NoProgress,
+            &cancel,
+        );
+        assert!(matches!(result, Err(PocError::Invalid(_))));
+    }
+
+    #[test]
+    fn hits() {
+        // little-endian works, nonce n has work n * 7 % 100
+        let work = |nonce: u32| {
+            let mut work = [0u8; 32];
+            work[31] = (nonce * 7 % 100) as u8;
+            work.to_vec()
+        };
+        let expect = |mut nonces: Vec<u32>| {
+            nonces.sort_by_key(|nonce| nonce * 7 % 100);
+            nonces
+                .into_iter()
+                .map(|nonce| (nonce, work(nonce)))
+                .collect::<Vec<_>>()
+        };
+        let seek = |mode: SeekMode| {
+            // two threads merged like multi seeking
+            let mut hits = Hits::new(mode);
+            let mut other = Hits::new(mode);
+            for nonce in 0..100u32 {
+                match nonce % 2 {
+                    0 => hits.push(nonce, &work(nonce)),
+                    _ => other.push(nonce, &work(nonce)),
+                }
+            }
+            hits.merge(other);
+            hits.into_works().unwrap()
+        };
+        assert_eq!(seek(SeekMode::All), expect((0..100).collect()));
+        assert_eq!(seek(SeekMode::TopK(3)), expect(vec![0, 43, 86]));
+        assert_eq!(seek(SeekMode::Best), expect(vec![0]));
+        assert_eq!(seek(SeekMode::First).len(), 1);
+        assert_eq!(
+            Hits::new(SeekMode::All).into_works(),
+            Err(PocError::NotFound)
+        );
+    }
+
+    #[test]
+    fn farm_validation() {
+        let addr = [1u8; 21];
+        let params = tiny_params();
+        let disk0 = tempdir().unwrap();
+        let disk1 = tempdir().unwrap();
+        // sparse files of only the first hash, checked by loading
+        let mut verifier = PocVerifier::with_params(params, PocVersion::V1);
+        let mut touch = |dir: &std::path::Path, flag: &str, addr: &[u8], start, end, first| {
+            let (name, _) = plot_name(flag, addr, start, end);
+            let mut fs = std::fs::File::create(dir.join(name)).unwrap();
+            std::io::Write::write_all(&mut fs, &verifier.scope_hash(addr, first, 0)).unwrap();
+            fs.set_len(plot_bytes(&params, end - start)).unwrap();
+        };
+        touch(disk0.path(), "optimized", &addr, 0, 100, 0);
+        touch(disk1.path(), "optimized", &addr, 100, 200, 100);
+        touch(disk1.path(), "unoptimized", &addr, 0, 200, 0);
+        let load = |dirs: &[&std::path::Path]| PlotFarm::load_with_params(&addr, dirs, &params);
+        let farm = load(&[disk0.path(), disk1.path()]).unwrap();
+        assert_eq!(farm.disks.len(), 2);
+        assert_eq!(farm.nonces(), 200);
+
+        // hashed by other version or start
+        let other = tempdir().unwrap();
+        touch(other.path(), "optimized", &addr, 200, 300, 0);
+        assert!(matches!(load(&[other.path()]), Err(PocError::Invalid(_))));
+
+        // overlap nonce range
+        touch(disk1.path(), "optimized", &addr, 50, 150, 50);
+        let result = load(&[disk0.path(), disk1.path()]);
+        assert!(matches!(result, Err(PocError::Invalid(_))));
+
+        // another address
+        touch(disk0.path(), "optimized", &[2u8; 21], 0, 100, 0);
+        assert!(matches!(load(&[disk0.path()]), Err(PocError::Invalid(_))));
+    }
+
+    #[test]
+    fn farm_cancelled() {
+        struct CancelAfterFile(CancelToken);
+        impl ProgressSink for CancelAfterFile {
+            fn finished(&self, _elapsed: std::time::Duration) {
+                self.0.cancel();
+            }
+        }
+
+        let (tmp, cancel, config) = tiny_setup(8);
+        let addr = [11u8; 21];
+        for (start, end) in [(0, 20), (20, 40)].iter() {
+            plot_unoptimized_file(
+                &addr,
+                *start,
+                *end,
+                tmp.path(),
+                &config,
+                &NoProgress,
+                &cancel,
+            )
+            .unwrap();
+        }
+        let farm = PlotFarm::load_with_params(&addr, &[tmp.path()], &config.params).unwrap();
+        let seek = |sink: &dyn ProgressSink, cancel: &CancelToken| {
+            farm.seek(
+                PocVersion::V1,
+                &[6u8; 32],
+                &[0xffu8; 32],
+                100,
+                SeekMode::All,
+                sink,
+                cancel,
+            )
+        };
+        assert_eq!(seek(&NoProgress, &cancel).unwrap().len(), 40);
+
+        // works of the first file are thrown away, same as seek_plot
+        let cancelled = CancelToken::new();
+        let result = seek(&CancelAfterFile(cancelled.clone()), &cancelled);
+        assert_eq!(result, Err(PocError::Cancelled));
+    }
+
+    /// sparse plot file name and size
+    fn plot_name(flag: &str, addr: &[u8], start: usize, end: usize) -> (String, u64) {
+        let name = format!("{}.{}-{}-{}.dat", flag, hex::encode(addr), start, end);
+        (name, ((end - start) * LOOP_COUNT * HASH_LEN) as u64)
+    }
+
+    #[test]
+    fn scan_dir() {
+        let addr = [1u8; 21];
+        let tmp = tempdir().unwrap();
+        let touch = |(name, len): (String, u64)| {
+            let fs = std::fs::File::create(tmp.path().join(name)).unwrap();
+            fs.set_len(len).unwrap();
+        };
+        touch(plot_name("unoptimized", &addr, 0, 2));
+        touch(plot_name("unoptimized", &addr, 4, 6));
+        touch(plot_name("unoptimized", &addr, 5, 6));
+        touch(plot_name("optimized", &addr, 0, 6));
+        touch(plot_name("optimized", &[2u8; 21], 3, 4));
+
+        // rejected files
+        let (name, _) = plot_name("optimized", &addr, 6, 8);
+        touch((name, 100));
+        touch(plot_name("optimized", &addr[..20], 0, 1));
+        touch(("unoptimized.01-0-99999999999999999999999.dat".to_owned(), 0));
+        touch(("unoptimized.01-3-3.dat".to_owned(), 0));
+        touch(("optimized.xyz.dat".to_owned(), 0));
+        std::fs::create_dir(tmp.path().join(plot_name("optimized", &addr, 8, 9).0)).unwrap();
+
+        // ignored files
+        touch(("readme.txt".to_owned(), 0));
+        touch(("unoptimized.0101-0-1.tmp".to_owned(), 0));
+
+        let scan = PlotFile::scan_dir(tmp.path(), &PocParams::MAINNET).unwrap();
+        let ranges = scan
+            .plots
+            .iter()
+            .map(|plot| (plot.addr[0], plot.flag.clone(), plot.start, plot.end))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ranges,
+            vec![
+                (1, PlotFlag::Unoptimized, 0, 2),
+                (1, PlotFlag::Unoptimized, 4, 6),
+                (1, PlotFlag::Unoptimized, 5, 6),
+                (1, PlotFlag::Optimized, 0, 6),
+                (2, PlotFlag::Optimized, 3, 4),
+            ]
+        );
+        assert_eq!(scan.rejected.len(), 6);
+        assert!(scan
+            .rejected
+            .iter()
+            .any(|rejected| rejected.reason == "file is 100bytes, expect 1048576bytes"));
+        assert_eq!(scan.groups().len(), 3);
+        assert_eq!(scan.overlaps().len(), 1);
+        assert_eq!(scan.overlaps()[0].1.start, 5);
+        assert_eq!(
+            scan.gaps(),
+            vec![(addr.to_vec(), PlotFlag::Unoptimized, PocVersion::V1, 2..4)]
+        );
+
+        // unreadable directory
+        let result = PlotFile::scan_dir(&tmp.path().join("missing"), &PocParams::MAINNET);
+        assert!(matches!(result, Err(PocError::Io(_))));
+    }
+
+    #[test]
+    fn reader() {
+        let tmp = tempdir().unwrap();
+        let path = tmp.path().join("optimized.dat");
+        let data = (0..2 * LOOP_COUNT * HASH_LEN)
+            .map(|index| (index / 64) as u8)
+            .collect::<Vec<u8>>();
+        std::fs::write(&path, &data).unwrap();
+
+        // mapped and buffered read same bytes
+        let mapped = PlotReader::open(&path, PlotFlag::Optimized, 10, 12).unwrap();
+        let buffered = PlotReader::open_buffered(&path, PlotFlag::Optimized, 10, 12).unwrap();
+        assert!(mapped.is_mapped());
+        assert!(!buffered.is_mapped());
+        for scope in [0, 1, 100, SCOPE_COUNT - 1].iter() {
+            let expect = &data[scope * 64..scope * 64 + 64];
+            assert_eq!(mapped.scope_slice(*scope).unwrap().as_ref(), expect);
+            assert_eq!(buffered.scope_slice(*scope).unwrap().as_ref(), expect);
+        }
+        assert!(mapped.scope_slice(SCOPE_COUNT).is_err());
+
+        // same file as two unoptimized nonces
+        let mapped = PlotReader::open(&path, PlotFlag::Unoptimized, 10, 12).unwrap();
+        let buffered = PlotReader::open_buffered(&path, PlotFlag::Unoptimized, 10, 12).unwrap();
+        for scope in [0, 1, 100, SCOPE_COUNT - 1].iter() {
+            let mut expect = data[scope * 32..scope * 32 + 32].to_vec();
+            let second = LOOP_COUNT * HASH_LEN + scope * 32;
+            expect.extend_from_slice(&data[second..second + 32]);
+            assert_eq!(mapped.scope_slice(*scope).unwrap().as_ref(), &expect[..]);
+            assert_eq!(buffered.scope_slice(*scope).unwrap().as_ref(), &expect[..]);
+        }
+
+        // random access to a scope and a nonce of both layouts
+        for flag in [PlotFlag::Optimized, PlotFlag::Unoptimized].iter() {
+            let mapped = PlotReader::open(&path, flag.clone(), 10, 12).unwrap();
+            let buffered = PlotReader::open_buffered(&path, flag.clone(), 10, 12).unwrap();
+            let params = PocParams::MAINNET;
+            for reader in [&mapped, &buffered].iter() {
+                let mut nonce = vec![];
+                for scope in 0..SCOPE_COUNT {
+                    let pos = scope_offset(flag, &params, 2, 1, scope);
+                    assert_eq!(pos % 32, 0);
+                    nonce.extend_from_slice(&data[pos..pos + 32]);
+                }
+                assert_eq!(reader.read_nonce(11).unwrap().as_ref(), &nonce[..]);
+                assert_eq!(reader.read_scope(11, 100).unwrap(), &nonce[3200..3232]);
+                assert!(reader.read_nonce(12).is_err());
+                assert!(reader.read_scope(9, 0).is_err());
+                assert!(reader.read_scope(10, SCOPE_COUNT).is_err());
+            }
+        }
+
+        // short file
+        for flag in [PlotFlag::Optimized, PlotFlag::Unoptimized].iter() {
+            let mapped = PlotReader::open(&path, flag.clone(), 10, 13).unwrap();
+            let buffered = PlotReader::open_buffered(&path, flag.clone(), 10, 13).unwrap();
+            assert!(mapped.scope_slice(SCOPE_COUNT - 1).is_err());
+            assert!(buffered.scope_slice(SCOPE_COUNT - 1).is_err());
+        }
+
+        // mainnet unoptimized scope is read by pieces, or by windows of 16 nonces
+        // with the gap of HDDs
+        struct Counted(
+            MemoryStorage,
+            std::sync::Arc<std::sync::atomic::AtomicUsize>,
+        );
+        impl PlotStorage for Counted {
+            fn read_at(&self, pos: u64, buf: &mut [u8]) -> Result<(), PocError> {
+                self.1.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                self.0.read_at(pos, buf)
+            }
+            fn write_at(&mut self, pos: u64, data: &[u8]) -> Result<(), PocError> {
+                self.0.write_at(pos, data)
+            }
+            fn size(&self) -> Result<u64, PocError> {
+                self.0.size()
+            }
+            fn allocate(&mut self, len: u64) -> Result<(), PocError> {
+                self.0.allocate(len)
+            }
+            fn sync(&mut self) -> Result<(), PocError> {
+                self.0.sync()
+            }
+        }
+        let nonce_bytes = PocParams::MAINNET.nonce_bytes();
+        assert_eq!(coalesced_pieces(nonce_bytes, 32, None), 1);
+        assert_eq!(coalesced_pieces(64, 32, None), 8 * 1024 * 1024 / 64);
+        assert_eq!(coalesced_pieces(nonce_bytes, 32, Some(1024 * 1024)), 16);
+        let data = (0..40 * nonce_bytes)
+            .map(|index| (index / 32 % 251) as u8)
+            .collect::<Vec<u8>>();
+        let reads = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let storage = Counted(MemoryStorage::from(data.clone()), reads.clone());
+        let counted = PlotReader::from_storage(Box::new(storage), PlotFlag::Unoptimized, 0, 40);
+        let storage = Counted(MemoryStorage::from(data.clone()), reads.clone());
+        let joined = PlotReader::from_storage(Box::new(storage), PlotFlag::Unoptimized, 0, 40)
+            .with_coalesce_gap(1024 * 1024);
+        let path = tmp.path().join("unoptimized.dat");
+        std::fs::write(&path, &data).unwrap();
+        let mapped = PlotReader::open(&path, PlotFlag::Unoptimized, 0, 40).unwrap();
+        for scope in [0, 1234, SCOPE_COUNT - 1].iter() {
+            let expect = (0..40)
+                .flat_map(|nonce| {
+                    let pos = nonce * nonce_bytes + scope * 32;
+                    data[pos..pos + 32].to_vec()
+                })
+                .collect::<Vec<u8>>();
+            for (reader, count) in [(&counted, 40), (&joined, 3)].iter() {
+                reads.store(0, std::sync::atomic::Ordering::SeqCst);
+                assert_eq!(reader.scope_slice(*scope).unwrap().as_ref(), &expect[..]);
+                assert_eq!(reads.load(std::sync::atomic::Ordering::SeqCst), *count);
+            }
+            assert_eq!(mapped.scope_slice(*scope).unwrap().as_ref(), &expect[..]);
+        }
+
+        // stale seek stops reading at the next window
+        let cancelled = CancelToken::new();
+        cancelled.cancel();
+        reads.store(0, std::sync::atomic::Ordering::SeqCst);
+        let result = counted.scope_slice_cancellable(0, &cancelled);
+        assert!(matches!(result, Err(PocError::Cancelled)));
+        assert_eq!(reads.load(std::sync::atomic::Ordering::SeqCst), 0);
+        let result = mapped.scope_slice_cancellable(0, &cancelled);
+        assert!(matches!(result, Err(PocError::Cancelled)));
+    }
+
+    #[test]
+    fn storage() {
+        let (tmp, cancel, config) = tiny_setup(7);
+        let params = config.params;
+        let addr = [8u8; 21];
+        let config = PlotterConfig {
+            threads: 3,
+            max_memory: 0,
+            ..config
+        };
+        let file = plot_unoptimized_file(&addr, 10, 40, tmp.path(), &config, &NoProgress, &cancel)
+            .unwrap();
+        let unoptimized = std::fs::read(&file.path).unwrap();
+
+        // plotted in memory same as the file
+        let mut memory = MemoryStorage::new();
+        plot_storage(&addr, 10, 40, &mut memory, &config, &NoProgress, &cancel).unwrap();
+        assert_eq!(memory.as_slice(), &unoptimized[..]);
+
+        // converted in memory same as the file
+        let converter = ConverterConfig {
+            max_memory: 480,
+            ..ConverterConfig::default()
+        };
+        let out = tempdir().unwrap();
+        let optimized =
+            convert_to_optimized_file(vec![file], out.path(), &converter, &NoProgress, &cancel)
+                .unwrap();
+        let data = std::fs::read(&optimized.path).unwrap();
+        let mut converted = MemoryStorage::new();
+        convert_storage(
+            &memory,
+            &mut converted,
+            30,
+            &params,
+            &converter,
+            &NoProgress,
+            &cancel,
+        )
+        .unwrap();
+        assert_eq!(converted.as_slice(), &data[..]);
+        let short = MemoryStorage::from(vec![0u8; 1024]);
+        let result = convert_storage(
+            &short,
+            &mut MemoryStorage::new(),
+            30,
+            &params,
+            &converter,
+            &NoProgress,
+            &cancel,
+        );
+        assert!(matches!(result, Err(PocError::Invalid(_))));
+
+        // seeked in memory same as the file
+        let previous_hash = [4u8; 32];
+        let target = [0xffu8; 32];
+        let reader = PlotReader::from_storage(Box::new(converted), PlotFlag::Optimized, 10, 40)
+            .with_params(params);
+        assert_eq!(
+            reader.read_nonce(17).unwrap().as_ref(),
+            &unoptimized[7 * 1024..8 * 1024]
+        );
+        let works = seek_reader(
+            &reader,
+            &previous_hash,
+            &target,
+            100,
+            SeekMode::TopK(5),
+            false,
+            &NoProgress,
+            &cancel,
+        )
+        .unwrap();
+        assert_eq!(works.len(), 5);
+        let expect = seek_plot(
+            &optimized,
+            &previous_hash,
+            &target,
+            100,
+            SeekMode::TopK(5),
+            false,
+            &NoProgress,
+            &cancel,
+        )
+        .unwrap();
+        assert_eq!(works, expect);
+
+        // regular file stands for a partition, locked while opened
+        let device = tmp.path().join("device");
+        std::fs::write(&device, vec![0u8; 32 * 1024]).unwrap();
+        let mut storage = DeviceStorage::open(&device).unwrap();
+        assert!(is_locked(&device).unwrap());
+        assert_eq!(storage.size().unwrap(), 32 * 1024);
+        plot_storage(&addr, 10, 40, &mut storage, &config, &NoProgress, &cancel).unwrap();
+        drop(storage);
+        let written = std::fs::read(&device).unwrap();
+        assert_eq!(&written[..30 * 1024], &unoptimized[..]);
+        assert_eq!(&written[30 * 1024..], &[0u8; 2048][..]);
+
+        // device too small fails before writing
+        std::fs::write(&device, vec![0u8; 29 * 1024]).unwrap();
+        let mut storage = DeviceStorage::open(&device).unwrap();
+        let result = plot_storage(&addr, 10, 40, &mut storage, &config, &NoProgress, &cancel);
+        assert_eq!(
+            result,
+            Err(PocError::NoSpace {
+                needed: 30 * 1024,
+                available: 29 * 1024
+            })
+        );
+        drop(storage);
+        assert_eq!(std::fs::read(&device).unwrap(), vec![0u8; 29 * 1024]);
+    }
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn checksums() {
+        let (tmp, cancel, config) = tiny_setup(8);
+        let params = config.params;
+        let addr = [6u8; 21];
+        let config = PlotterConfig {
+            threads: 3,
+            max_memory: 0,
+            checksum_nonces: Some(4),
+            ..config
+        };
+        let wrong = PlotterConfig {
+            checksum_nonces: Some(3),
+            ..config.clone()
+        };
+        let result = plot_unoptimized_file(&addr, 10, 40, tmp.path(), &wrong, &NoProgress, &cancel);
+        assert!(matches!(result, Err(PocError::Invalid(_))));
+
+        // 30 nonces of 8 blocks, last one is 2 nonces
+        let plot = plot_unoptimized_file(&addr, 10, 40, tmp.path(), &config, &NoProgress, &cancel)
+            .unwrap();
+        assert_eq!(
+            PlotFile::scan_dir(tmp.path(), &params).unwrap().plots,
+            vec![plot.clone()]
+        );
+        let table = PlotReader::from_plot(&plot)
+            .unwrap()
+            .checksums()
+            .unwrap()
+            .unwrap();
+        assert_eq!((table.blocks(), table.block_range(7)), (8, 28..30));
+        // checksums of a scope are contiguous, the next scope follows the last block
+        assert_eq!(table.position(0, 1) - table.position(7, 0), 8);
+        let unoptimized = std::fs::read(&plot.path).unwrap();
+        assert_eq!(unoptimized.len() as u64, 30 * 1024 + table.trailer_bytes());
+        assert!(scrub_plot(&plot, &NoProgress, &cancel).unwrap().is_empty());
+
+        // converted data is same with or without checksums, table is same for both layouts
+        let converter = ConverterConfig {
+            max_memory: 480,
+            checksum_nonces: Some(4),
+            ..ConverterConfig::default()
+        };
+        let out = tempdir().unwrap();
+        let optimized = convert_to_optimized_file(
+            vec![plot.clone()],
+            out.path(),
+            &converter,
+            &NoProgress,
+            &cancel,
+        )
+        .unwrap();
+        let data = std::fs::read(&optimized.path).unwrap();
+        let plain = tempdir().unwrap();
+        let plain = convert_to_optimized_file(
+            vec![plot.clone()],
+            plain.path(),
+            &ConverterConfig {
+                checksum_nonces: None,
+                ..converter.clone()
+            },
+            &NoProgress,
+            &cancel,
+        )
+        .unwrap();
+        let plain_data = std::fs::read(&plain.path).unwrap();
+        assert_eq!(&data[..30 * 1024], &plain_data[..]);
+        assert_eq!(&data[30 * 1024..], &unoptimized[30 * 1024..]);
+        assert!(scrub_plot(&optimized, &NoProgress, &cancel)
+            .unwrap()
+            .is_empty());
+        assert!(matches!(
+            scrub_plot(&plain, &NoProgress, &cancel),
+            Err(PocError::Invalid(_))
+        ));
+
+        // in memory, split and merged back same as the file
+        let mut memory = MemoryStorage::from(unoptimized.clone());
+        let mut converted = MemoryStorage::new();
+        convert_storage(
+            &memory,
+            &mut converted,
+            30,
+            &params,
+            &converter,
+            &NoProgress,
+            &cancel,
+        )
+        .unwrap();
+        assert_eq!(converted.as_slice(), &data[..]);
+        plot_storage(&addr, 10, 40, &mut memory, &config, &NoProgress, &cancel).unwrap();
+        assert_eq!(memory.as_slice(), &unoptimized[..]);
+        let halves = tempdir().unwrap();
+        let (lower, upper) = split_optimized(
+            &optimized,
+            13,
+            halves.path(),
+            halves.path(),
+            &converter,
+            &NoProgress,
+            &cancel,
+        )
+        .unwrap();
+        assert!(scrub_plot(&lower, &NoProgress, &cancel).unwrap().is_empty());
+        assert!(scrub_plot(&upper, &NoProgress, &cancel).unwrap().is_empty());
+        let merged = tempdir().unwrap();
+        let merged = merge_optimized(
+            vec![lower.clone(), upper.clone()],
+            merged.path(),
+            &converter,
+            &NoProgress,
+            &cancel,
+        )
+        .unwrap();
+        assert_eq!(std::fs::read(&merged.path).unwrap(), data);
+
+        // inputs mismatched checksums are not merged
+        let mut broken = std::fs::read(&upper.path).unwrap();
+        broken[3 * 27 * 32 + 1] ^= 1;
+        std::fs::write(&upper.path, &broken).unwrap();
+        let result = merge_optimized(
+            vec![lower, upper],
+            tempdir().unwrap().path(),
+            &converter,
+            &NoProgress,
+            &cancel,
+        );
+        assert_eq!(result, Err(PocError::Corrupted(vec![13..17])));
+
+        // a flipped hash is skipped by seeking its scope and found by scrubbing
+        #[derive(Default)]
+        struct Corrupted(std::sync::Mutex<Vec<std::ops::Range<usize>>>);
+        impl ProgressSink for Corrupted {
+            fn corrupted(&self, ranges: &[std::ops::Range<usize>]) {
+                self.0.lock().unwrap().extend_from_slice(ranges);
+            }
+        }
+        let previous_hash = [3u8; 32];
+        let target = [0xffu8; 32];
+        let scope = params.scope_index(&previous_hash);
+        let seek = |plot: &PlotFile, sink: &Corrupted| {
+            seek_plot(
+                plot,
+                &previous_hash,
+                &target,
+                100,
+                SeekMode::All,
+                false,
+                sink,
+                &cancel,
+            )
+        };
+        let sink = Corrupted::default();
+        assert_eq!(seek(&optimized, &sink).unwrap().len(), 30);
+        assert!(sink.0.lock().unwrap().is_empty());
+        let mut broken = data.clone();
+        broken[(scope * 30 + 13) * 32 + 5] ^= 1;
+        std::fs::write(&optimized.path, &broken).unwrap();
+        let works = seek(&optimized, &sink).unwrap();
+        assert_eq!(works.len(), 26);
+        assert!(works.iter().all(|(nonce, _)| !(22..26).contains(nonce)));
+        assert_eq!(*sink.0.lock().unwrap(), vec![22..26]);
+        let sink = Corrupted::default();
+        let (_, nonce, _) = seek_time_window(
+            &optimized,
+            &previous_hash,
+            &target,
+            100..102,
+            &sink,
+            &cancel,
+        )
+        .unwrap();
+        assert!(!(22..26).contains(&nonce));
+        assert_eq!(*sink.0.lock().unwrap(), vec![22..26]);
+        assert_eq!(
+            PocError::Corrupted(vec![22..26, 30..31]).to_string(),
+            "corrupted nonces 22-26, 30-31"
+        );
+        assert_eq!(
+            scrub_plot(&optimized, &NoProgress, &cancel).unwrap(),
+            vec![22..26]
+        );
+        let result = split_optimized(
+            &optimized,
+            13,
+            tempdir().unwrap().path(),
+            tempdir().unwrap().path(),
+            &converter,
+            &NoProgress,
+            &cancel,
+        );
+        assert_eq!(result, Err(PocError::Corrupted(vec![22..26])));
+        let mut broken = unoptimized.clone();
+        broken[28 * 1024 + 100] ^= 1;
+        broken[1024 + 7] ^= 1;
+        broken[2 * 1024 + 9] ^= 1;
+        std::fs::write(&plot.path, &broken).unwrap();
+        assert_eq!(
+            scrub_plot(&plot, &NoProgress, &cancel).unwrap(),
+            vec![10..14, 38..40]
+        );
+    }
+
+    #[test]
+    fn version() {
+        assert_eq!(PocVersion::from_height(100000), PocVersion::V1);
+        assert_eq!(PocVersion::from_height(POC_V2_HEIGHT), PocVersion::V2);
+        let addr = [3u8; 21];
+        let previous_hash = [7u8; 32];
+        let target = [0xffu8; 32];
+
+        // v2 hashes differ from v1, lanes output same hashes
+        let mut output = get_generator_output();
+        let mut cache = get_generator_cache();
+        let params = PocParams::MAINNET;
+        poc_generator_by_version(&params, PocVersion::V2, &addr, 5, &mut output, &mut cache);
+        let v1 = get_poc_hash_by_version(&params, PocVersion::V1, &addr, 5, 10, &previous_hash);
+        let v2 = get_poc_hash_by_version(&params, PocVersion::V2, &addr, 5, 10, &previous_hash);
+        assert_ne!(v1, v2);
+        let mut outputs = vec![0u8; 2 * LOOP_COUNT * HASH_LEN];
+        let mut caches = vec![get_generator_cache(), cache];
+        poc_generator_many(&params, PocVersion::V2, &addr, 4, &mut outputs, &mut caches);
+        assert_eq!(&outputs[output.len()..], output.as_ref());
+
+        // v2 plot is named with version and seeked by v2 work
+        let tmp = tempdir().unwrap();
+        let config = PlotterConfig {
+            version: PocVersion::V2,
+            ..PlotterConfig::default()
+        };
+        let cancel = CancelToken::new();
+        let plot =
+            plot_unoptimized_file(&addr, 4, 6, tmp.path(), &config, &NoProgress, &cancel).unwrap();
+        assert!(plot.path.to_str().unwrap().ends_with("-4-6.v2.dat"));
+        assert_eq!(
+            PlotFile::restore_from_dir(tmp.path()).unwrap(),
+            vec![plot.clone()]
+        );
+        let works = seek_plot(
+            &plot,
+            &previous_hash,
+            &target,
+            10,
+            SeekMode::All,
+            false,
+            &NoProgress,
+            &cancel,
+        )
+        .unwrap();
+        let work = works.iter().find(|(nonce, _)| *nonce == 5).unwrap();
+        assert_eq!(work.1, v2);
+        let mut verifier = PocVerifier::with_version(PocVersion::V2);
+        let verified = verifier.verify(&addr, 5, 10, &previous_hash, &target);
+        assert_eq!(verified.unwrap().to_vec(), v2);
+
+        // reader takes the version of the name, v1 seekers refuse it
+        let reader = PlotReader::open(&plot.path, PlotFlag::Unoptimized, 4, 6).unwrap();
+        assert_eq!(reader.version(), PocVersion::V2);
+        assert_eq!(reader.check_version(&addr), Ok(()));
+        let result = seek_file_by_mode(
+            &plot.path,
+            4,
+            6,
+            &previous_hash,
+            &target,
+            10,
+            SeekMode::All,
+            false,
+            &NoProgress,
+            &cancel,
+        );
+        assert!(matches!(result, Err(PocError::Invalid(_))));
+        let mislabeled = PlotFile {
+            version: PocVersion::V1,
+            ..plot.clone()
+        };
+        assert!(matches!(
+            PlotReader::from_plot(&mislabeled),
+            Err(PocError::Invalid(_))
+        ));
+
+        // renamed without suffix is found by hashes
+        let renamed = tempdir().unwrap();
+        let path = renamed.path().join(plot.path.file_name().unwrap());
+        let path = path.to_str().unwrap().replace(".v2.dat", ".dat");
+        std::fs::copy(&plot.path, &path).unwrap();
+        let plots = PlotFile::restore_from_dir(renamed.path()).unwrap();
+        assert_eq!(plots[0].version, PocVersion::V1);
+        let reader = PlotReader::from_plot(&plots[0]).unwrap();
+        assert!(matches!(
+            reader.check_version(&addr),
+            Err(PocError::Invalid(_))
+        ));
+
+        // v1 farm ignores v2 plots
+        let farm = PlotFarm::load(&addr, &[tmp.path()]).unwrap();
+        let result = farm.seek(
+            PocVersion::V1,
+            &previous_hash,
+            &target,
+            10,
+            SeekMode::All,
+            &NoProgress,
+            &cancel,
+        );
+        assert_eq!(result, Err(PocError::NotFound));
+    }
+
     #[test]
     fn poc() {
         // height 100000
@@ -183,4 +2372,59 @@ mod tests {
         let work_hash = get_poc_hash(&addr, nonce, time, &previous_hash);
         assert_eq!(hex::encode(work_hash), work);
     }
+
+    #[test]
+    fn verifier() {
+        // height 100000
+        let work = "d8fc394861e265ff9fa43fc9de408b6a26d631b993ba73b4048bd885b0090000";
+        let addr = s2h("00de6e40c12db0920348ed0ebb136e3a926bad4a3a");
+        let nonce = 685;
+        let time = 1579609665 - 1557883103;
+        let previous_hash = s2h("df98f659f3f31cbf3494b96e44697729e3d018b6308a6de8fefa5fd4b378d025");
+
+        // verifier reused for other nonce
+        let mut verifier = PocVerifier::new();
+        let mut output = get_generator_output();
+        let mut cache = get_generator_cache();
+        poc_generator(&addr, 3, &mut output, &mut cache);
+        assert_eq!(&verifier.scope_hash(&addr, 3, 100)[..], &output[3200..3232]);
+        let target = s2h("0000000000000000000000000000000000000000000000000000000000ff0000");
+        let verified = verifier.verify(&addr, nonce, time, &previous_hash, &target);
+        assert_eq!(hex::encode(verified.unwrap()), work);
+        let target = s2h("0000000000000000000000000000000000000000000000000000000000080000");
+        let verified = verifier.verify(&addr, nonce, time, &previous_hash, &target);
+        assert!(matches!(verified, Err(PocError::Invalid(_))));
+    }
+
+    #[test]
+    fn lanes() {
+        let addr = s2h("00de6e40c12db0920348ed0ebb136e3a926bad4a3a");
+
+        // lanes output same hashes
+        let mut output = get_generator_output();
+        let mut cache = get_generator_cache();
+        poc_generator(&addr, 3, &mut output, &mut cache);
+        let mut outputs = vec![0u8; 3 * LOOP_COUNT * HASH_LEN];
+        let mut caches = vec![get_generator_cache(), get_generator_cache(), cache];
+        let params = PocParams::MAINNET;
+        poc_generator_many(&params, PocVersion::V1, &addr, 1, &mut outputs, &mut caches);
+        assert_eq!(&outputs[2 * output.len()..], output.as_ref());
+        poc_generator(&addr, 1, &mut output, &mut caches[0]);
+        assert_eq!(&outputs[..output.len()], output.as_ref());
+
+        // more caches than lanes are hashed by groups
+        let tiny = tiny_params();
+        let mut outputs = vec![0u8; (POC_LANES + 3) * tiny.nonce_bytes()];
+        let mut caches = (0..POC_LANES + 3)
+            .map(|_| tiny.generator_cache())
+            .collect::<Vec<_>>();
+        poc_generator_many(&tiny, PocVersion::V1, &addr, 5, &mut outputs, &mut caches);
+        let mut output = tiny.generator_output();
+        let mut cache = tiny.generator_cache();
+        for (index, expect) in outputs.chunks(tiny.nonce_bytes()).enumerate() {
+            let nonce = 5 + index as u32;
+            poc_generator_by_version(&tiny, PocVersion::V1, &addr, nonce, &mut output, &mut cache);
+            assert_eq!(expect, output.as_ref());
+        }
+    }
 }
@@ -0,0 +1,196 @@
+use crate::cancel::CancelToken;
+use crate::error::PocError;
+use crate::plotfile::{Address, PlotFile, PlotFlag};
+use crate::progress::ProgressSink;
+use crate::reader::PlotReader;
+use crate::seekfile::{seek_plot, Hits, SeekMode};
+use crate::{PocParams, PocVersion};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+/// plot files in one directory, seeked by one reader thread
+#[derive(Clone, Debug, PartialEq)]
+pub struct FarmDisk {
+    pub dir: PathBuf,
+    pub files: Vec<PlotFile>,
+}
+
+/// plot files of one address spread over many directories
+///
+/// recommend one directory a disk, because each is read by own thread
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlotFarm {
+    pub addr: Address,
+    pub disks: Vec<FarmDisk>,
+}
+
+impl PlotFarm {
+    /// load mainnet plot files of the address from directories
+    ///
+    /// error if another address's plot found, nonce ranges overlap or a plot is
+    /// not hashed by the version of its name
+    pub fn load<P: AsRef<Path>>(addr: &[u8], dirs: &[P]) -> Result<Self, PocError> {
+        PlotFarm::load_with_params(addr, dirs, &PocParams::MAINNET)
+    }
+
+    /// load plot files of the address and params from directories
+    ///
+    /// first nonce of each file is hashed, ex. v2 plot renamed without suffix
+    pub fn load_with_params<P: AsRef<Path>>(
+        addr: &[u8],
+        dirs: &[P],
+        params: &PocParams,
+    ) -> Result<Self, PocError> {
+        let mut disks = Vec::with_capacity(dirs.len());
+        for dir in dirs {
+            let dir = dir.as_ref();
+            if !dir.is_dir() {
+                return Err(PocError::Invalid(format!("not a directory {:?}", dir)));
+            }
+            let files = PlotFile::scan_dir(dir, params)?.plots;
+            if let Some(plot) = files.iter().find(|plot| plot.addr != addr) {
+                return Err(PocError::Invalid(format!(
+                    "address is not {}: {:?}",
+                    hex::encode(addr),
+                    plot
+                )));
+            }
+            for plot in files.iter() {
+                let reader = PlotReader::from_plot(plot)?;
+                reader.check_version(addr).map_err(|err| match err {
+                    PocError::Invalid(err) => PocError::Invalid(format!("{} in {:?}", err, plot)),
+                    err => err,
+                })?;
+            }
+            disks.push(FarmDisk {
+                dir: dir.to_path_buf(),
+                files,
+            });
+        }
+        let farm = PlotFarm {
+            addr: addr.to_vec(),
+            disks,
+        };
+        farm.check_overlap()?;
+        Ok(farm)
+    }
+
+    /// all plot files of the flag ordered by start
+    pub fn files(&self, flag: PlotFlag) -> Vec<&PlotFile> {
+        let mut files = self
+            .disks
+            .iter()
+            .flat_map(|disk| disk.files.iter())
+            .filter(|plot| plot.flag == flag)
+            .collect::<Vec<&PlotFile>>();
+        files.sort_by_key(|plot| plot.start);
+        files
+    }
+
+    /// total nonces of optimized files
+    pub fn nonces(&self) -> usize {
+        self.files(PlotFlag::Optimized)
+            .iter()
+            .map(|plot| plot.end - plot.start)
+            .sum()
+    }
+
+    /// identical nonces never help, so same flag and version files must not overlap
+    fn check_overlap(&self) -> Result<(), PocError> {
+        for flag in [PlotFlag::Unoptimized, PlotFlag::Optimized].iter() {
+            let mut files = self.files(flag.clone());
+            files.sort_by_key(|plot| (plot.version, plot.start));
+            for pair in files.windows(2) {
+                if pair[0].version == pair[1].version && pair[1].start < pair[0].end {
+                    return Err(PocError::Invalid(format!(
+                        "nonce range overlap {:?} and {:?}",
+                        pair[0], pair[1]
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// seek all optimized files and unoptimized files not converted yet of the
+    /// version, collect works by the mode across the farm
+    ///
+    /// return [(nonce, workHash),..] ordered from the lowest work, a failed disk
+    /// is skipped while others found work
+    #[allow(clippy::too_many_arguments)]
+    pub fn seek(
+        &self,
+        version: PocVersion,
+        previous_hash: &[u8],
+        target: &[u8],
+        time: u32,
+        mode: SeekMode,
+        progress: &dyn ProgressSink,
+        cancel: &CancelToken,
+    ) -> Result<Vec<(u32, Vec<u8>)>, PocError> {
+        // unoptimized files are seeked only while not converted
+        let optimized = self.files(PlotFlag::Optimized);
+        let is_seekable = |plot: &PlotFile| match plot.flag {
+            _ if plot.version != version => false,
+            PlotFlag::Optimized => true,
+            PlotFlag::Unoptimized => !optimized.iter().any(|other| {
+                other.version == version && other.start < plot.end && plot.start < other.end
+            }),
+        };
+        let results = thread::scope(|scope| {
+            let handles = self
+                .disks
+                .iter()
+                .map(|disk| {
+                    let is_seekable = &is_seekable;
+                    scope.spawn(move || {
+                        let mut results = vec![];
+                        for plot in disk.files.iter().filter(|plot| is_seekable(plot)) {
+                            // note: disk speed is the bottleneck, seek by single thread
+                            let result = seek_plot(
+                                plot,
+                                previous_hash,
+                                target,
+                                time,
+                                mode,
+                                false,
+                                progress,
+                                cancel,
+                            );
+                            results.push(result);
+                        }
+                        results
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().expect("farm reader panicked"))
+                .collect::<Vec<_>>()
+        });
+
+        // select works across files
+        let mut hits = Hits::new(mode);
+        let mut error = None;
+        for result in results {
+            match result {
+                Ok(works) => works
+                    .iter()
+                    .for_each(|(nonce, work)| hits.push(*nonce, work)),
+                Err(PocError::NotFound) | Err(PocError::Cancelled) => continue,
+                Err(err) => {
+                    error.get_or_insert(err);
+                }
+            }
+        }
+        // note: works found before cancel are useless for caller, same as seek_reader
+        if !hits.is_enough() && cancel.is_cancelled() {
+            return Err(PocError::Cancelled);
+        }
+        match (hits.into_works(), error) {
+            (Ok(works), _) => Ok(works),
+            (Err(_), Some(err)) => Err(err),
+            (Err(err), None) => Err(err),
+        }
+    }
+}
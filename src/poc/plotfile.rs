@@ -1,59 +1,184 @@
+use crate::cancel::CancelToken;
+use crate::capacity::{check_space, needed_space, plot_bytes};
+use crate::checksum::{nonce_checksums, BandChecksums, Checksums};
+use crate::error::PocError;
+use crate::lock::{open_exclusive, open_shared};
+use crate::progress::{Phase, ProgressSink, ProgressTracker, StageRates};
+use crate::reader::scope_offset;
+use crate::storage::{FileStorage, PlotStorage};
 use crate::*;
 use regex::Regex;
-use std::cmp::min;
+use std::cmp::{max, min};
+use std::collections::BTreeMap;
 use std::fmt;
-use std::fs::{read_dir, rename, File};
-use std::io::{stdout, BufWriter, Read, Seek, SeekFrom, Write};
+use std::fs::{read_dir, remove_file, rename, File, OpenOptions};
+use std::io::{Read, Write};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
-use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
-type Address = [u8; 21];
+/// plot address, `PocParams::addr_len` bytes
+pub type Address = Vec<u8>;
 
-/// working thread limit like semaphore
-#[derive(Clone)]
-struct Semaphore {
-    count: Arc<Mutex<usize>>,
-    locked: bool,
+/// worker pool settings for plotting
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlotterConfig {
+    /// hashing thread number
+    pub threads: usize,
+    /// upper bytes of hashed chunks waiting for disk write
+    pub max_memory: usize,
+    /// nonce number one task generates (one nonce is 512KiB)
+    pub chunk_nonces: usize,
+    /// poc version of plotted hashes
+    pub version: PocVersion,
+    /// poc geometry of plotted hashes
+    pub params: PocParams,
+    /// reserve full file size before writing, not fragmented on HDDs
+    pub preallocate: bool,
+    /// write bypassing page cache if the file system allows (linux O_DIRECT)
+    pub direct_io: bool,
+    /// nonces of a checksum block after the plot data, must divide chunk_nonces.
+    /// none for plot data only
+    pub checksum_nonces: Option<usize>,
 }
 
-impl Semaphore {
-    /// default limitation is cpu number
-    fn new() -> Self {
-        let cpu_count = num_cpus::get();
-        Semaphore {
-            count: Arc::new(Mutex::new(cpu_count)),
-            locked: false,
+impl Default for PlotterConfig {
+    /// all cpus, 1GiB memory and 16 nonces (8MiB) a chunk of mainnet v1, preallocated
+    fn default() -> Self {
+        PlotterConfig {
+            threads: num_cpus::get(),
+            max_memory: 1024 * 1024 * 1024,
+            chunk_nonces: 16,
+            version: PocVersion::V1,
+            params: PocParams::MAINNET,
+            preallocate: true,
+            direct_io: false,
+            checksum_nonces: None,
         }
     }
+}
+
+impl PlotterConfig {
+    /// bytes of one chunk buffer
+    pub fn chunk_bytes(&self) -> usize {
+        self.chunk_nonces * self.params.nonce_bytes()
+    }
 
-    // acquire lock and decrement
-    fn acquire(&mut self) {
-        assert!(!self.locked);
+    /// chunk buffers allowed to exist at once, at least one
+    pub fn buffer_count(&self) -> usize {
+        max(1, self.max_memory / self.chunk_bytes())
+    }
+}
+
+/// memory settings for converting
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConverterConfig {
+    /// upper bytes of a tile transposed in memory
+    pub max_memory: usize,
+    /// reserve full file size before writing, not fragmented on HDDs
+    pub preallocate: bool,
+    /// write bypassing page cache if the file system allows (linux O_DIRECT)
+    pub direct_io: bool,
+    /// nonces of a checksum block after the plot data, none for plot data only
+    pub checksum_nonces: Option<usize>,
+}
+
+impl Default for ConverterConfig {
+    /// 256MiB, tile of 4096 nonces x 2048 scopes, preallocated
+    fn default() -> Self {
+        ConverterConfig {
+            max_memory: 256 * 1024 * 1024,
+            preallocate: true,
+            direct_io: false,
+            checksum_nonces: None,
+        }
+    }
+}
+
+impl ConverterConfig {
+    /// scopes of a tile, near square root of the tile for balanced read and write size
+    ///
+    /// note: capped by mainnet scopes, converter caps again by scopes of the plot
+    pub fn tile_scopes(&self) -> usize {
+        let scopes = ((self.max_memory / 32) as f64).sqrt() as usize;
+        match scopes {
+            0 | 1 => 1,
+            scopes => min(SCOPE_COUNT, 1 << (usize::BITS - 1 - scopes.leading_zeros())),
+        }
+    }
+
+    /// nonces of a tile, at least one
+    pub fn tile_nonces(&self) -> usize {
+        max(1, self.max_memory / (32 * self.tile_scopes()))
+    }
+}
+
+/// reusable chunk buffers, hashing workers wait for a free one
+///
+/// buffers are allocated on demand up to the limit and never freed until done
+struct BufferPool {
+    state: Mutex<PoolState>,
+    condvar: Condvar,
+    buffer_bytes: usize,
+}
+
+struct PoolState {
+    free: Vec<Vec<u8>>,
+    allocated: usize,
+    limit: usize,
+    closed: bool,
+}
+
+impl BufferPool {
+    fn new(limit: usize, buffer_bytes: usize) -> Self {
+        BufferPool {
+            state: Mutex::new(PoolState {
+                free: vec![],
+                allocated: 0,
+                limit,
+                closed: false,
+            }),
+            condvar: Condvar::new(),
+            buffer_bytes,
+        }
+    }
+
+    // wait for a free buffer, none after closed
+    fn take(&self) -> Option<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
         loop {
-            {
-                let mut count = self.count.lock().unwrap();
-                if 0 < *count {
-                    *count -= 1;
-                    self.locked = true;
-                    return;
-                }
+            if state.closed {
+                return None;
+            }
+            if let Some(buffer) = state.free.pop() {
+                return Some(buffer);
             }
-            // wait for another thread release
-            thread::sleep(Duration::from_secs(1));
+            if state.allocated < state.limit {
+                state.allocated += 1;
+                return Some(vec![0u8; self.buffer_bytes]);
+            }
+            state = self.condvar.wait(state).unwrap();
         }
     }
 
-    // release lock and increment
-    fn release(self) {
-        assert!(self.locked);
-        *self.count.lock().unwrap() += 1;
+    // give back and wake up a waiting thread
+    fn put(&self, buffer: Vec<u8>) {
+        self.state.lock().unwrap().free.push(buffer);
+        self.condvar.notify_one();
+    }
+
+    // wake up and stop all waiting threads
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.condvar.notify_all();
     }
 }
 
 /// plot file status flag
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum PlotFlag {
     Unoptimized,
     Optimized,
@@ -66,6 +191,10 @@ pub struct PlotFile {
     pub addr: Address,
     pub start: usize,
     pub end: usize,
+    /// written in file name, no suffix is v1
+    pub version: PocVersion,
+    /// not written in file, given by who scans or plots
+    pub params: PocParams,
 }
 
 impl fmt::Debug for PlotFile {
@@ -75,280 +204,1152 @@ impl fmt::Debug for PlotFile {
             .field(&self.path)
             .field(&hex::encode(&self.addr))
             .field(&format!("{}-{}", self.start, self.end))
+            .field(&self.version)
             .finish()
     }
 }
 
-impl PlotFile {
-    pub fn restore_from_dir(dir: &Path) -> Vec<Self> {
-        // 1=flag, 2=addr, 3=start, 4=end
-        let re =
-            Regex::new("^(unoptimized|optimized)\\.([a-f0-9]+)-([0-9]+)-([0-9]+)\\.dat$").unwrap();
-        let mut result = vec![];
-        // check the dir
-        for path in read_dir(dir).unwrap() {
-            let path = path.unwrap().path();
-            let name = path.file_name().unwrap().to_str().unwrap();
-            match re.captures(name) {
-                Some(c) => {
-                    if c.len() != 5 {
-                        continue;
+/// a plot-like file skipped by directory scan
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rejected {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+/// plot files found in a directory
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PlotScan {
+    /// valid plots ordered by address, flag and start
+    pub plots: Vec<PlotFile>,
+    /// files named like plots but not usable
+    pub rejected: Vec<Rejected>,
+    /// unoptimized plots left in XX.inplace by cancelled `optimize_in_place`,
+    /// named as XX.dat to resume by it. not seekable until finished
+    pub pending: Vec<PlotFile>,
+}
+
+impl PlotScan {
+    /// valid plots grouped by address, layout and version, each ordered by start
+    pub fn groups(&self) -> BTreeMap<(Address, PlotFlag, PocVersion), Vec<&PlotFile>> {
+        let mut groups: BTreeMap<_, Vec<&PlotFile>> = BTreeMap::new();
+        for plot in self.plots.iter() {
+            groups
+                .entry((plot.addr.clone(), plot.flag.clone(), plot.version))
+                .or_default()
+                .push(plot);
+        }
+        groups
+    }
+
+    /// pairs of same group files sharing nonces
+    pub fn overlaps(&self) -> Vec<(&PlotFile, &PlotFile)> {
+        let mut overlaps = vec![];
+        for files in self.groups().values() {
+            for (index, plot) in files.iter().enumerate() {
+                for other in files[index + 1..].iter() {
+                    if other.start < plot.end {
+                        overlaps.push((*plot, *other));
                     }
-                    let flag = match c.get(1).unwrap().as_str() {
-                        "unoptimized" => PlotFlag::Unoptimized,
-                        "optimized" => PlotFlag::Optimized,
-                        _ => continue,
-                    };
-                    let addr = match hex::decode(c.get(2).unwrap().as_str()) {
-                        Ok(addr) => {
-                            let mut array: Address = [0u8; 21];
-                            array.clone_from_slice(addr.as_slice());
-                            array
-                        }
-                        Err(_) => continue,
-                    };
-                    let start: usize = c.get(3).unwrap().as_str().parse().unwrap();
-                    let end: usize = c.get(4).unwrap().as_str().parse().unwrap();
-                    result.push(PlotFile {
-                        flag,
-                        path,
-                        addr,
-                        start,
-                        end,
-                    })
                 }
-                None => continue,
             }
         }
-        // ordered by start index
-        result.sort_by(|a, b| {
-            if a.flag == b.flag {
-                a.start.cmp(&b.start)
-            } else {
-                match (&a.flag, &b.flag) {
-                    (PlotFlag::Unoptimized, PlotFlag::Optimized) => a.start.cmp(&0usize),
-                    (PlotFlag::Optimized, PlotFlag::Unoptimized) => b.start.cmp(&0usize),
-                    (a, b) => panic!(format!("unexpected PlotFLag compare? {:?} with {:?}", a, b)),
+        overlaps
+    }
+
+    /// nonce ranges not plotted between files of same group
+    pub fn gaps(&self) -> Vec<(Address, PlotFlag, PocVersion, Range<usize>)> {
+        let mut gaps = vec![];
+        for ((addr, flag, version), files) in self.groups() {
+            let mut end = files[0].end;
+            for plot in files.iter().skip(1) {
+                if end < plot.start {
+                    gaps.push((addr.clone(), flag.clone(), version, end..plot.start));
+                }
+                end = max(end, plot.end);
+            }
+        }
+        gaps
+    }
+}
+
+impl PlotFile {
+    /// scan the directory for plot files, never panic on strange files
+    ///
+    /// `.tmp`, `.resume` and other files not named like plots are ignored,
+    /// file size is checked by the params. error only if the directory can't be read
+    pub fn scan_dir(dir: &Path, params: &PocParams) -> Result<PlotScan, PocError> {
+        // 1=flag, 2=addr, 3=start, 4=end, 5=version
+        let re = Regex::new(
+            "^(unoptimized|optimized)\\.([a-f0-9]+)-([0-9]+)-([0-9]+)(\\.v[0-9]+)?\\.dat$",
+        )
+        .unwrap();
+        let mut scan = PlotScan::default();
+        for entry in read_dir(dir)? {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(err) => {
+                    scan.rejected.push(Rejected {
+                        path: dir.to_path_buf(),
+                        reason: format!("read entry: {}", err),
+                    });
+                    continue;
+                }
+            };
+            let name = match path.file_name() {
+                Some(name) => name.to_string_lossy().into_owned(),
+                None => continue,
+            };
+            if !(name.starts_with("unoptimized.") || name.starts_with("optimized.")) {
+                continue;
+            }
+            if let Some(stem) = name.strip_suffix(".inplace") {
+                // note: cut from the end while converting, the size is not checked
+                let name = format!("{}.dat", stem);
+                match Self::parse_name(&re, &dir.join(&name), &name, params) {
+                    Ok(plot) if plot.flag == PlotFlag::Unoptimized => scan.pending.push(plot),
+                    Ok(_) => scan.rejected.push(Rejected {
+                        path,
+                        reason: "in-place converting of optimized file".to_owned(),
+                    }),
+                    Err(reason) => scan.rejected.push(Rejected { path, reason }),
                 }
+                continue;
+            }
+            if !name.ends_with(".dat") {
+                continue;
             }
+            match Self::check_plot(&re, &path, &name, params) {
+                Ok(plot) => scan.plots.push(plot),
+                Err(reason) => scan.rejected.push(Rejected { path, reason }),
+            }
+        }
+        scan.plots.sort_by(|a, b| {
+            (&a.addr, &a.flag, a.version, a.start, a.end)
+                .cmp(&(&b.addr, &b.flag, b.version, b.start, b.end))
         });
-        result
+        scan.rejected.sort_by(|a, b| a.path.cmp(&b.path));
+        scan.pending.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(scan)
+    }
+
+    /// valid mainnet plots in the directory ordered by address, flag, version and start
+    pub fn restore_from_dir(dir: &Path) -> Result<Vec<Self>, PocError> {
+        Ok(Self::scan_dir(dir, &PocParams::MAINNET)?.plots)
+    }
+
+    /// parse the plot name and check file size
+    fn check_plot(re: &Regex, path: &Path, name: &str, params: &PocParams) -> Result<Self, String> {
+        let plot = Self::parse_name(re, path, name, params)?;
+
+        // both layouts hold all hashes of the nonces
+        let (start, end) = (plot.start, plot.end);
+        let expect = (end - start)
+            .checked_mul(params.nonce_bytes())
+            .ok_or(format!("nonce range {}-{} overflows", start, end))?;
+        let meta = path
+            .metadata()
+            .map_err(|err| format!("metadata: {}", err))?;
+        if !meta.is_file() {
+            return Err("not a regular file".to_owned());
+        }
+        if (expect as u64) < meta.len() {
+            // checksum trailer after the data
+            let fs = File::open(path).map_err(|err| format!("open: {}", err))?;
+            Checksums::read(&FileStorage::new(fs), params, end - start)
+                .map_err(|err| err.to_string())?;
+        } else if meta.len() != expect as u64 {
+            return Err(format!(
+                "file is {}bytes, expect {}bytes",
+                meta.len(),
+                expect
+            ));
+        }
+        Ok(plot)
+    }
+
+    /// plot of the path by its name
+    fn parse_name(re: &Regex, path: &Path, name: &str, params: &PocParams) -> Result<Self, String> {
+        if path.file_name().and_then(|name| name.to_str()).is_none() {
+            return Err("file name is not utf-8".to_owned());
+        }
+        let c = re.captures(name).ok_or("malformed plot name")?;
+        let flag = match &c[1] {
+            "unoptimized" => PlotFlag::Unoptimized,
+            _ => PlotFlag::Optimized,
+        };
+        let addr = match hex::decode(&c[2]) {
+            Ok(bytes) if bytes.len() == params.addr_len() => bytes,
+            Ok(bytes) => {
+                return Err(format!(
+                    "address is {} bytes, not {}",
+                    bytes.len(),
+                    params.addr_len()
+                ))
+            }
+            Err(err) => return Err(format!("address hex: {}", err)),
+        };
+        let start: usize = c[3]
+            .parse()
+            .map_err(|_| format!("start {} overflows", &c[3]))?;
+        let end: usize = c[4]
+            .parse()
+            .map_err(|_| format!("end {} overflows", &c[4]))?;
+        if end <= start {
+            return Err(format!("empty nonce range {}-{}", start, end));
+        }
+        let suffix = c.get(5).map_or("", |m| m.as_str());
+        let version = PocVersion::from_suffix(suffix)
+            .ok_or_else(|| format!("unknown version {}", &suffix[1..]))?;
+        Ok(PlotFile {
+            flag,
+            path: path.to_path_buf(),
+            addr,
+            start,
+            end,
+            version,
+            params: *params,
+        })
     }
 }
 
 /// plot unoptimized file
 ///
 /// recommend **SSD** for tmp_dir
-pub fn plot_unoptimized_file(addr: &Address, start: usize, end: usize, tmp_dir: &Path) -> PlotFile {
-    assert!(tmp_dir.is_dir());
+///
+/// finished chunks are recorded to a `.resume` journal, so plotting
+/// cancelled (or killed) restarts from the left tasks by same arguments.
+/// `PocError::Invalid` if the journal has other `checksum_nonces`,
+/// `PocError::NoSpace` before writing if the left nonces don't fit tmp_dir
+pub fn plot_unoptimized_file(
+    addr: &[u8],
+    start: usize,
+    end: usize,
+    tmp_dir: &Path,
+    config: &PlotterConfig,
+    progress: &dyn ProgressSink,
+    cancel: &CancelToken,
+) -> Result<PlotFile, PocError> {
+    let mut plots = plot_unoptimized_files(addr, start, end, &[tmp_dir], config, progress, cancel)?;
+    Ok(plots.remove(0))
+}
 
-    // create file object
-    let tmp = Path::new(tmp_dir).join(format!(
-        "unoptimized.{}-{}-{}.tmp",
-        hex::encode(addr),
+/// plot unoptimized files striped over the dirs, ex. one dir of each empty HDD
+///
+/// the range is split to equal parts in order of dirs. one hashing worker
+/// pool is shared and each file is written by own thread, so all disks are
+/// busy at once. resumable by same arguments like `plot_unoptimized_file`
+pub fn plot_unoptimized_files(
+    addr: &[u8],
+    start: usize,
+    end: usize,
+    dirs: &[&Path],
+    config: &PlotterConfig,
+    progress: &dyn ProgressSink,
+    cancel: &CancelToken,
+) -> Result<Vec<PlotFile>, PocError> {
+    assert!(dirs.iter().all(|dir| dir.is_dir()));
+    assert!(start < end);
+    assert!(0 < config.threads);
+    assert!(0 < config.chunk_nonces);
+    check_addr(addr, &config.params)?;
+    check_checksum_nonces(config)?;
+    if dirs.is_empty() || end - start < dirs.len() {
+        return Err(PocError::Invalid(format!(
+            "cannot split {}-{} to {} dirs",
+            start,
+            end,
+            dirs.len()
+        )));
+    }
+
+    // create file objects, or reopen to resume
+    let mut targets: Vec<PlotTarget> = Vec::with_capacity(dirs.len());
+    let parts = dirs.len();
+    for (index, dir) in dirs.iter().enumerate() {
+        let part_start = start + (end - start) * index / parts;
+        let part_end = start + (end - start) * (index + 1) / parts;
+        match PlotTarget::open(addr, part_start, part_end, dir, config) {
+            Ok(target) => targets.push(target),
+            Err(err) => {
+                // nothing is left by the failed job
+                for target in targets {
+                    target.discard()?;
+                }
+                return Err(err);
+            }
+        }
+    }
+    let mut outputs = targets
+        .iter_mut()
+        .map(|target| PlotOutput {
+            start: target.start,
+            end: target.end,
+            storage: &mut target.fs,
+            journal: Some(&mut target.journal),
+            checksums: target.checksums,
+            tasks: std::mem::take(&mut target.tasks),
+        })
+        .collect::<Vec<_>>();
+    plot_outputs(addr, &mut outputs, config, progress, cancel)?;
+    targets
+        .into_iter()
+        .map(|target| target.finish(addr, config))
+        .collect()
+}
+
+/// plot unoptimized nonces of the range to a storage from its first byte
+///
+/// ex. `MemoryStorage` for small ranges, `DeviceStorage` for a partition.
+/// no journal, cancelled plotting starts over.
+/// `PocError::NoSpace` before writing if the storage cannot hold the range
+pub fn plot_storage(
+    addr: &[u8],
+    start: usize,
+    end: usize,
+    storage: &mut dyn PlotStorage,
+    config: &PlotterConfig,
+    progress: &dyn ProgressSink,
+    cancel: &CancelToken,
+) -> Result<(), PocError> {
+    assert!(start < end);
+    assert!(0 < config.threads);
+    assert!(0 < config.chunk_nonces);
+    check_addr(addr, &config.params)?;
+    check_checksum_nonces(config)?;
+    let checksums = Checksums::with(&config.params, end - start, config.checksum_nonces);
+    let trailer_bytes = checksums.map_or(0, |table| table.trailer_bytes());
+    storage.allocate(plot_bytes(&config.params, end - start) + trailer_bytes)?;
+    let mut outputs = [PlotOutput {
         start,
-        end
-    ));
-    let mut fs = BufWriter::new(File::create(&tmp).unwrap());
-
-    // generate hash
-    let semaphore = Semaphore::new();
-    let task_num = 1000;
-    let step_size = (end - start) / task_num + 1;
-
-    // note: block channel when disk io is busy
-    let (tx, rx) = mpsc::sync_channel(4);
-
-    // throw tasks
-    let mut start_pos = start.clone();
-    for _ in 0..task_num {
-        let end_pos = min(end, start_pos + step_size);
-        let addr = addr.clone();
-        let tx = tx.clone();
-        let mut semaphore = semaphore.clone();
-        thread::spawn(move || {
-            // wait for lock get
-            semaphore.acquire();
-
-            // generate hash
-            let mut cache = get_generator_cache();
-            let mut output = get_generator_output();
-            let mut result = Vec::with_capacity(output.len() * step_size as usize);
-            for nonce in (start_pos as u32)..(end_pos as u32) {
-                poc_generator(&addr, nonce, &mut output, &mut cache);
-                result.extend_from_slice(output.as_ref());
+        end,
+        storage: &mut *storage,
+        journal: None,
+        checksums,
+        tasks: split_tasks(start, end, config.chunk_nonces, &[]),
+    }];
+    plot_outputs(addr, &mut outputs, config, progress, cancel)?;
+    storage.sync()
+}
+
+fn check_addr(addr: &[u8], params: &PocParams) -> Result<(), PocError> {
+    if addr.len() != params.addr_len() {
+        return Err(PocError::Invalid(format!(
+            "plot address is {} bytes, not {}",
+            addr.len(),
+            params.addr_len()
+        )));
+    }
+    Ok(())
+}
+
+/// chunks hold whole checksum blocks, checksums are made by hashing workers
+fn check_checksum_nonces(config: &PlotterConfig) -> Result<(), PocError> {
+    match config.checksum_nonces {
+        Some(block_nonces)
+            if block_nonces == 0 || !config.chunk_nonces.is_multiple_of(block_nonces) =>
+        {
+            Err(PocError::Invalid(format!(
+                "checksum block of {} nonces does not divide chunk of {} nonces",
+                block_nonces, config.chunk_nonces
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// one output file of plotting, locked until finished or dropped
+struct PlotTarget {
+    start: usize,
+    end: usize,
+    resume: bool,
+    tmp: PathBuf,
+    journal_path: PathBuf,
+    fs: FileStorage,
+    journal: File,
+    checksums: Option<Checksums>,
+    /// left chunks of the file
+    tasks: Vec<(usize, usize)>,
+}
+
+impl PlotTarget {
+    /// `PocError::NoSpace` before writing if the left nonces don't fit dir
+    fn open(
+        addr: &[u8],
+        start: usize,
+        end: usize,
+        dir: &Path,
+        config: &PlotterConfig,
+    ) -> Result<Self, PocError> {
+        let params = config.params;
+        let name = plot_stem(&PlotFlag::Unoptimized, addr, start, end, config.version);
+        let tmp = dir.join(format!("{}.tmp", name));
+        let journal_path = dir.join(format!("{}.resume", name));
+        let resume = tmp.exists();
+        // note: locked before the journal is read, other plotter of the range fails here
+        let raw_fs = open_exclusive(&tmp)?;
+        let finished = if resume {
+            read_journal(&journal_path)
+        } else {
+            vec![]
+        };
+        if !finished.is_empty() {
+            check_journal_header(&journal_path, config.checksum_nonces)?;
+        }
+
+        // fail before writing if the space not held by the tmp file yet doesn't fit
+        let checksums = Checksums::with(&params, end - start, config.checksum_nonces);
+        let trailer_bytes = checksums.map_or(0, |table| table.trailer_bytes());
+        let total_bytes = plot_bytes(&params, end - start) + trailer_bytes;
+        if let Err(err) = check_space(dir, needed_space(&raw_fs, total_bytes)?) {
+            if !resume {
+                remove_file(&tmp)?;
             }
+            return Err(err);
+        }
+        let journal = if finished.is_empty() {
+            raw_fs.set_len(0)?;
+            create_journal(&journal_path, config.checksum_nonces)?
+        } else {
+            OpenOptions::new().append(true).open(&journal_path)?
+        };
+        let fs = FileStorage::output(
+            raw_fs,
+            &tmp,
+            total_bytes,
+            config.preallocate,
+            config.direct_io,
+        )?;
+        Ok(PlotTarget {
+            start,
+            end,
+            resume,
+            tmp,
+            journal_path,
+            fs,
+            journal,
+            checksums,
+            tasks: split_tasks(start, end, config.chunk_nonces, &finished),
+        })
+    }
 
-            // send result
-            tx.send((start_pos, end_pos, result)).unwrap();
+    /// remove new tmp file and journal, resumed ones are left
+    fn discard(self) -> Result<(), PocError> {
+        if !self.resume {
+            remove_file(&self.tmp)?;
+            remove_file(&self.journal_path)?;
+        }
+        Ok(())
+    }
 
-            // release lock
-            semaphore.release();
-        });
-        // throw next task
-        start_pos = end_pos;
+    /// rename XX.tmp to XX.dat, then release file object and lock
+    fn finish(mut self, addr: &[u8], config: &PlotterConfig) -> Result<PlotFile, PocError> {
+        // note: tmp file of resumed or preallocated plotting may be longer
+        let trailer_bytes = self.checksums.map_or(0, |table| table.trailer_bytes());
+        let len = plot_bytes(&config.params, self.end - self.start) + trailer_bytes;
+        self.fs.set_len(len)?;
+        self.fs.sync()?;
+        let dst = self.tmp.with_extension("dat");
+        rename(&self.tmp, &dst)?;
+        remove_file(&self.journal_path)?;
+        Ok(PlotFile {
+            flag: PlotFlag::Unoptimized,
+            path: dst,
+            addr: addr.to_vec(),
+            start: self.start,
+            end: self.end,
+            version: config.version,
+            params: config.params,
+        })
+    }
+}
+
+/// storage of a nonce range written by the plotting pipeline
+struct PlotOutput<'a> {
+    start: usize,
+    end: usize,
+    storage: &'a mut dyn PlotStorage,
+    /// finished chunks are recorded if any
+    journal: Option<&'a mut File>,
+    /// checksums of chunks are written after the data if any
+    checksums: Option<Checksums>,
+    /// left chunks of the range
+    tasks: Vec<(usize, usize)>,
+}
+
+/// hash left chunks of all outputs and write them
+///
+/// pipeline: hashing workers fill pooled buffers, a writer thread of each
+/// output writes them in nonce order and this thread reports progress.
+/// chunks of outputs are handed out in turn, so all outputs are written at once.
+/// note: a worker takes a buffer before its task, and the buffer is given
+/// back after written, so memory is limited to max_memory
+fn plot_outputs(
+    addr: &[u8],
+    targets: &mut [PlotOutput],
+    config: &PlotterConfig,
+    progress: &dyn ProgressSink,
+    cancel: &CancelToken,
+) -> Result<(), PocError> {
+    let params = config.params;
+    let pool = Arc::new(BufferPool::new(config.buffer_count(), config.chunk_bytes()));
+    // (target, index in target, start_pos, end_pos)
+    let mut tasks = vec![];
+    let longest = targets.iter().map(|target| target.tasks.len()).max();
+    for index in 0..longest.unwrap_or(0) {
+        for (target, plot) in targets.iter().enumerate() {
+            if let Some(&(start_pos, end_pos)) = plot.tasks.get(index) {
+                tasks.push((target, index, start_pos, end_pos));
+            }
+        }
     }
+    let tasks = Arc::new(tasks);
+    let next_task = Arc::new(AtomicUsize::new(0));
+    let task_num = tasks.len();
+    let (txs, rxs): (Vec<_>, Vec<_>) = targets.iter().map(|_| mpsc::channel()).unzip();
 
-    // wait for all thread finish
-    let offset = start.clone();
+    // note: chunks start at checksum blocks, checked by check_checksum_nonces
+    let mut block_nonces = Vec::with_capacity(targets.len());
+    for target in targets.iter_mut() {
+        if let Some(table) = &target.checksums {
+            table.write_header(target.storage)?;
+        }
+        block_nonces.push(target.checksums.map(|table| table.block_nonces));
+    }
+    let block_nonces = Arc::new(block_nonces);
+
+    // start workers
+    let worker_num = min(min(config.threads, config.buffer_count()), task_num);
+    let mut workers = Vec::with_capacity(worker_num);
+    for _ in 0..worker_num {
+        let addr = addr.to_vec();
+        let txs = txs.clone();
+        let pool = pool.clone();
+        let tasks = tasks.clone();
+        let next_task = next_task.clone();
+        let block_nonces = block_nonces.clone();
+        let cancel = cancel.clone();
+        let version = config.version;
+        workers.push(thread::spawn(move || {
+            let mut caches = (0..POC_LANES)
+                .map(|_| params.generator_cache())
+                .collect::<Vec<Box<[u8]>>>();
+            // wait for free buffer
+            while let Some(mut buffer) = pool.take() {
+                // get next task
+                let index = next_task.fetch_add(1, Ordering::SeqCst);
+                if task_num <= index || cancel.is_cancelled() {
+                    pool.put(buffer);
+                    return;
+                }
+                let (target, target_index, start_pos, end_pos) = tasks[index];
+
+                // generate hash, lanes of nonces at once
+                let now = Instant::now();
+                for first in (start_pos..end_pos).step_by(POC_LANES) {
+                    if cancel.is_cancelled() {
+                        // throw away half-done chunk
+                        pool.put(buffer);
+                        return;
+                    }
+                    let lanes = min(POC_LANES, end_pos - first);
+                    let pos = params.nonce_bytes() * (first - start_pos);
+                    poc_generator_many(
+                        &params,
+                        version,
+                        &addr,
+                        first as u32,
+                        &mut buffer[pos..pos + params.nonce_bytes() * lanes],
+                        &mut caches[..lanes],
+                    );
+                }
+                let sums = block_nonces[target].map(|block_nonces| {
+                    let bytes = params.nonce_bytes() * (end_pos - start_pos);
+                    nonce_checksums(&params, &buffer[..bytes], block_nonces)
+                });
+
+                // send result to the writer of the target
+                if txs[target]
+                    .send((target_index, buffer, sums, now.elapsed()))
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        }));
+    }
+    drop(txs);
+
+    // report progress and stage rates until all writers finish
+    let nonces = targets.iter().map(|target| target.end - target.start).sum();
+    let total_bytes = tasks
+        .iter()
+        .map(|(_, _, start_pos, end_pos)| ((end_pos - start_pos) * params.nonce_bytes()) as u64)
+        .sum();
+    let mut tracker = ProgressTracker::start(progress, Phase::Plotting, nonces, total_bytes);
+    let writer_num = targets.len();
+    let pool = &pool;
+    let written = thread::scope(|scope| {
+        let (report_tx, report_rx) = mpsc::channel();
+        let writers = targets
+            .iter_mut()
+            .zip(rxs)
+            .map(|(target, rx)| {
+                let report_tx = report_tx.clone();
+                scope.spawn(move || {
+                    let written = write_target(target, rx, report_tx, pool, &params);
+                    if written.is_err() {
+                        // wake up and stop all workers
+                        pool.close();
+                    }
+                    written
+                })
+            })
+            .collect::<Vec<_>>();
+        drop(report_tx);
+        let mut total = StageReport::default();
+        for report in report_rx.iter() {
+            tracker.chunk_done(report.nonces, report.bytes);
+            total.add(&report);
+            tracker.stage_rates(&total.rates(worker_num, writer_num));
+        }
+        writers
+            .into_iter()
+            .map(|writer| writer.join().expect("plotting writer panicked"))
+            .collect::<Result<Vec<usize>, PocError>>()
+    });
+    for worker in workers {
+        worker.join().expect("plotting worker panicked");
+    }
+    let step: usize = written?.iter().sum();
+    if step < task_num && cancel.is_cancelled() {
+        // leave tmp and journal for resume
+        return Err(PocError::Cancelled);
+    }
+    assert_eq!(step, task_num);
+    tracker.phase_changed(Phase::Finalizing);
+    Ok(())
+}
+
+/// chunk hashed by a worker: (index in target, buffer, checksums, hashing time)
+type HashedChunk = (usize, Vec<u8>, Option<Vec<u8>>, Duration);
+
+/// write chunks of the target sequentially, out of order chunks wait in
+/// pending. left chunks are written when workers stopped by cancel
+fn write_target(
+    target: &mut PlotOutput,
+    rx: mpsc::Receiver<HashedChunk>,
+    report_tx: mpsc::Sender<StageReport>,
+    pool: &BufferPool,
+    params: &PocParams,
+) -> Result<usize, PocError> {
+    let mut pending = BTreeMap::new();
+    let mut next = 0;
     let mut step = 0;
-    let now = Instant::now();
-    for (start_pos, end_pos, result) in rx.iter().take(task_num) {
-        let first_pos = LOOP_COUNT * HASH_LEN * (start_pos - offset);
-        fs.seek(SeekFrom::Start(first_pos as u64)).unwrap();
-        fs.write(result.as_slice()).unwrap();
-        // check end position
-        let calc_end_pos = fs.seek(SeekFrom::Current(0)).unwrap();
-        let estimate_pos = LOOP_COUNT * HASH_LEN * (end_pos - offset);
-        assert_eq!(calc_end_pos, estimate_pos as u64);
-
-        // show progress
-        step += 1;
-        if cfg!(feature = "progress-bar") {
-            print_progress_bar(&now, step, task_num);
+    let mut write_ready = |pending: &mut BTreeMap<usize, HashedChunk>,
+                           next: &mut usize,
+                           flush: bool|
+     -> Result<usize, PocError> {
+        let mut report = StageReport::default();
+        let mut finished = vec![];
+        let now = Instant::now();
+        while let Some(index) = pending
+            .keys()
+            .next()
+            .copied()
+            .filter(|index| flush || index == next)
+        {
+            let (_, buffer, sums, hashing) = pending.remove(&index).unwrap();
+            let (start_pos, end_pos) = target.tasks[index];
+            let first_pos = params.nonce_bytes() * (start_pos - target.start);
+            let bytes = params.nonce_bytes() * (end_pos - start_pos);
+            target
+                .storage
+                .write_at(first_pos as u64, &buffer[..bytes])?;
+            if let (Some(table), Some(sums)) = (&target.checksums, sums) {
+                // note: checksums of chunk blocks are contiguous in each scope
+                let block = (start_pos - target.start) / table.block_nonces;
+                let len = sums.len() / table.scope_count;
+                for (scope, sums) in sums.chunks(len).enumerate() {
+                    target
+                        .storage
+                        .write_at(table.position(block, scope), sums)?;
+                }
+            }
+            finished.push((start_pos, end_pos));
+            pool.put(buffer);
+            *next = index + 1;
+            report.nonces += end_pos - start_pos;
+            report.bytes += bytes as u64;
+            report.hashing += hashing;
+            report.chunks += 1;
+        }
+        // record after data reached the disk, one sync for the written chunks
+        if let Some(journal) = &mut target.journal {
+            if !finished.is_empty() {
+                target.storage.sync()?;
+            }
+            for (start_pos, end_pos) in finished {
+                writeln!(journal, "{}-{}", start_pos, end_pos)?;
+            }
+        }
+        let chunks = report.chunks;
+        if 0 < chunks {
+            report.writing = now.elapsed();
+            report_tx.send(report).ok();
+        }
+        Ok(chunks)
+    };
+    for chunk in rx {
+        pending.insert(chunk.0, chunk);
+        step += write_ready(&mut pending, &mut next, false)?;
+    }
+    step += write_ready(&mut pending, &mut next, true)?;
+    Ok(step)
+}
+
+/// read finished nonce ranges from plotting journal
+///
+/// broken lines (ex. killed on writing) are ignored
+pub(crate) fn read_journal(path: &Path) -> Vec<(usize, usize)> {
+    let mut data = String::new();
+    match File::open(path) {
+        Ok(mut fs) => {
+            if fs.read_to_string(&mut data).is_err() {
+                return vec![];
+            }
         }
+        Err(_) => return vec![],
     }
+    parse_journal(&data)
+}
 
-    // release file objext
-    fs.flush().unwrap();
-    std::mem::drop(fs);
+/// new journal starting with the checksum block size the tmp file is written with
+fn create_journal(path: &Path, checksum_nonces: Option<usize>) -> Result<File, PocError> {
+    let mut journal = File::create(path)?;
+    writeln!(journal, "checksum_nonces={}", checksum_nonces.unwrap_or(0))?;
+    Ok(journal)
+}
 
-    // rename XX.tmp to XX.dat
-    let dst = Path::new(tmp_dir).join(format!(
-        "unoptimized.{}-{}-{}.dat",
-        hex::encode(addr),
-        start,
-        end
-    ));
-    rename(&tmp, &dst).unwrap();
-
-    // return unoptimized file path
-    PlotFile {
-        flag: PlotFlag::Unoptimized,
-        path: dst.to_path_buf(),
-        addr: addr.clone(),
-        start,
-        end,
+/// refuse to resume a tmp file written with other checksum block size
+///
+/// journals without the line are of tmp files without checksums
+fn check_journal_header(path: &Path, checksum_nonces: Option<usize>) -> Result<(), PocError> {
+    let data = std::fs::read_to_string(path)?;
+    let recorded = data
+        .lines()
+        .find_map(|line| line.strip_prefix("checksum_nonces="))
+        .and_then(|nonces| nonces.parse::<usize>().ok())
+        .filter(|nonces| 0 < *nonces);
+    if recorded != checksum_nonces {
+        return Err(PocError::Invalid(format!(
+            "{:?} resumes checksum_nonces {:?}, not {:?}",
+            path, recorded, checksum_nonces
+        )));
     }
+    Ok(())
+}
+
+/// parse "start-end" lines, broken lines are ignored
+pub(crate) fn parse_journal(data: &str) -> Vec<(usize, usize)> {
+    data.lines()
+        .filter_map(|line| {
+            let mut iter = line.split('-');
+            let start_pos = iter.next()?.parse().ok()?;
+            let end_pos = iter.next()?.parse().ok()?;
+            if start_pos < end_pos && iter.next().is_none() {
+                Some((start_pos, end_pos))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// split nonce range to tasks, except finished ranges
+fn split_tasks(
+    start: usize,
+    end: usize,
+    chunk_nonces: usize,
+    finished: &[(usize, usize)],
+) -> Vec<(usize, usize)> {
+    let mut tasks = vec![];
+    let mut pos = start;
+    while pos < end {
+        // skip finished range
+        if let Some((_, done_end)) = finished.iter().find(|(s, e)| *s <= pos && pos < *e) {
+            pos = *done_end;
+            continue;
+        }
+        // task stops before next finished range
+        let limit = finished
+            .iter()
+            .map(|(s, _)| *s)
+            .filter(|s| pos < *s)
+            .min()
+            .unwrap_or(end);
+        let task_end = min(min(pos + chunk_nonces, limit), end);
+        tasks.push((pos, task_end));
+        pos = task_end;
+    }
+    tasks
 }
 
 /// concat some unoptimized files to one optimized file
 ///
 /// recommend **HDD** for out_dir
-pub fn convert_to_optimized_file(files: Vec<PlotFile>, out_dir: &Path) -> PlotFile {
-    assert!(0 < files.len());
+///
+/// tiles of (nonces x scopes) are read to memory and transposed, finished
+/// scope bands are recorded to a `.resume` journal, so converting cancelled
+/// (or killed) restarts from the left bands by same arguments.
+/// `PocError::Invalid` if the journal has other `checksum_nonces`,
+/// `PocError::NoSpace` before writing if the left bands don't fit out_dir
+pub fn convert_to_optimized_file(
+    files: Vec<PlotFile>,
+    out_dir: &Path,
+    config: &ConverterConfig,
+    progress: &dyn ProgressSink,
+    cancel: &CancelToken,
+) -> Result<PlotFile, PocError> {
+    assert!(!files.is_empty());
     assert!(out_dir.is_dir());
 
     // check inputs status
     let addr = files.first().unwrap().addr.clone();
-    let start = files.first().unwrap().start.clone();
-    let end = files.last().unwrap().end.clone();
-    for (index, plot) in files.iter().enumerate().skip(1) {
+    let start = files.first().unwrap().start;
+    let end = files.last().unwrap().end;
+    let version = files.first().unwrap().version;
+    let params = files.first().unwrap().params;
+    for (index, plot) in files.iter().enumerate() {
         assert_eq!(plot.flag, PlotFlag::Unoptimized);
         assert_eq!(plot.addr, addr);
-        let previous = files.get(index - 1).unwrap();
-        assert_eq!(plot.start, previous.end);
+        assert_eq!(plot.version, version);
+        assert_eq!(plot.params, params);
+        if 0 < index {
+            assert_eq!(plot.start, files[index - 1].end);
+        }
     }
 
     // create file objects
-    let mut reader = files
+    let reader = files
         .iter()
-        // note: non-buffered file object is best for atomic read & seek
-        .map(|plot| File::open(&plot.path).unwrap())
-        .collect::<Vec<File>>();
-    let tmp = out_dir.join(format!(
-        "optimized.{}-{}-{}.tmp",
-        hex::encode(addr),
-        start,
-        end
-    ));
-    let mut writer = BufWriter::new(File::create(&tmp).unwrap());
-
-    // read and join
-    let now = Instant::now();
-    let mut buffer = [0u8; 32];
-    let skip_size = (LOOP_COUNT * HASH_LEN - 32) as i64;
-    let task_num = LOOP_COUNT * HASH_LEN / 32;
-    for step in 0..task_num as u64 {
-        let mut count = 0usize;
-        for (fs, plot) in reader.iter_mut().zip(files.iter()) {
-            // set first position to read
-            fs.seek(SeekFrom::Start(step * 32)).unwrap();
-            // reading..
-            loop {
-                match fs.read(&mut buffer) {
-                    Ok(32) => {
-                        count += 1;
-                        writer.write(&buffer).unwrap();
-                        // seek next section
-                        if fs.seek(SeekFrom::Current(skip_size)).is_err() {
-                            // over end of file
-                            break;
-                        }
-                    }
-                    Ok(0) => {
-                        // full seeked and next file
-                        break;
-                    }
-                    // unexpected errors
-                    Ok(size) => {
-                        panic!(format!("unexpected size({}bytes) reading {:?}", size, plot))
-                    }
-                    Err(err) => {
-                        panic!(format!("error occurred on converting: {}", err.to_string()))
-                    }
-                }
-            }
-        }
+        .map(|plot| Ok(FileStorage::new(open_shared(&plot.path)?)))
+        .collect::<Result<Vec<FileStorage>, PocError>>()?;
+    let reader = reader
+        .iter()
+        .map(|fs| fs as &dyn PlotStorage)
+        .collect::<Vec<_>>();
+    convert_range(
+        &files,
+        &reader,
+        start..end,
+        out_dir,
+        config,
+        progress,
+        cancel,
+    )
+}
 
-        // check nonce count
-        assert_eq!(count, end - start);
+/// write optimized file of the range from opened unoptimized files
+///
+/// the range may be a part of the files, readers are kept locked by caller
+pub(crate) fn convert_range(
+    files: &[PlotFile],
+    reader: &[&dyn PlotStorage],
+    range: Range<usize>,
+    out_dir: &Path,
+    config: &ConverterConfig,
+    progress: &dyn ProgressSink,
+    cancel: &CancelToken,
+) -> Result<PlotFile, PocError> {
+    let (start, end) = (range.start, range.end);
+    let addr = files.first().unwrap().addr.clone();
+    let version = files.first().unwrap().version;
+    let params = files.first().unwrap().params;
+    let name = plot_stem(&PlotFlag::Optimized, &addr, start, end, version);
+    let tmp = out_dir.join(format!("{}.tmp", name));
+    let journal_path = out_dir.join(format!("{}.resume", name));
+    let resume = tmp.exists();
+    let raw_fs = open_exclusive(&tmp)?;
 
-        // show progress
-        if cfg!(feature = "progress-bar") {
-            print_progress_bar(&now, step, task_num);
+    // resume after bands already written
+    let done_scopes = if resume {
+        finished_scopes(&read_journal(&journal_path), params.scope_count())
+    } else {
+        0
+    };
+    if 0 < done_scopes {
+        check_journal_header(&journal_path, config.checksum_nonces)?;
+    }
+    let checksums = Checksums::with(&params, end - start, config.checksum_nonces);
+    let trailer_bytes = checksums.map_or(0, |table| table.trailer_bytes());
+    let total_bytes = plot_bytes(&params, end - start) + trailer_bytes;
+    if let Err(err) = check_space(out_dir, needed_space(&raw_fs, total_bytes)?) {
+        if !resume {
+            remove_file(&tmp)?;
         }
+        return Err(err);
     }
+    let mut journal = if done_scopes == 0 {
+        raw_fs.set_len(0)?;
+        create_journal(&journal_path, config.checksum_nonces)?
+    } else {
+        OpenOptions::new().append(true).open(&journal_path)?
+    };
+    let mut writer = FileStorage::output(
+        raw_fs,
+        &tmp,
+        total_bytes,
+        config.preallocate,
+        config.direct_io,
+    )?;
+    let job = Transpose {
+        inputs: files
+            .iter()
+            .zip(reader)
+            .map(|(plot, fs)| (plot.start..plot.end, *fs))
+            .collect(),
+        range: start..end,
+        params,
+    };
+    let left_bytes = ((params.scope_count() - done_scopes) * (end - start) * 32) as u64;
+    let mut tracker = ProgressTracker::start(progress, Phase::Converting, end - start, left_bytes);
+    job.run(
+        &mut writer,
+        done_scopes,
+        Some(&mut journal),
+        config,
+        &mut tracker,
+        cancel,
+    )?;
+    tracker.phase_changed(Phase::Finalizing);
 
-    // release file objects
-    files.into_iter().for_each(drop);
-    writer.flush().unwrap();
+    // rename XX.tmp to XX.dat, then release file objects and locks
+    writer.set_len(total_bytes)?;
+    writer.sync()?;
+    let dst = out_dir.join(format!("{}.dat", name));
+    rename(&tmp, &dst)?;
+    remove_file(&journal_path)?;
     std::mem::drop(writer);
-
-    // rename XX.tmp to XX.dat
-    let dst = out_dir.join(format!(
-        "optimized.{}-{}-{}.dat",
-        hex::encode(addr),
-        start,
-        end
-    ));
-    rename(&tmp, &dst).unwrap();
+    std::mem::drop(journal);
 
     // success
-    PlotFile {
+    Ok(PlotFile {
         flag: PlotFlag::Optimized,
         path: dst,
         addr,
         start,
         end,
+        version,
+        params,
+    })
+}
+
+/// convert unoptimized nonces of a storage to optimized one in another storage
+///
+/// both hold the plot from the first byte, ex. `MemoryStorage` for tests.
+/// no journal, cancelled converting starts over
+pub fn convert_storage(
+    input: &dyn PlotStorage,
+    output: &mut dyn PlotStorage,
+    nonces: usize,
+    params: &PocParams,
+    config: &ConverterConfig,
+    progress: &dyn ProgressSink,
+    cancel: &CancelToken,
+) -> Result<(), PocError> {
+    assert!(0 < nonces);
+    let total_bytes = plot_bytes(params, nonces);
+    if input.size()? < total_bytes {
+        return Err(PocError::Invalid(format!(
+            "storage of {}bytes cannot hold {} nonces",
+            input.size()?,
+            nonces
+        )));
     }
+    let checksums = Checksums::with(params, nonces, config.checksum_nonces);
+    output.allocate(total_bytes + checksums.map_or(0, |table| table.trailer_bytes()))?;
+    let job = Transpose {
+        inputs: vec![(0..nonces, input)],
+        range: 0..nonces,
+        params: *params,
+    };
+    let mut tracker = ProgressTracker::start(progress, Phase::Converting, nonces, total_bytes);
+    job.run(output, 0, None, config, &mut tracker, cancel)?;
+    tracker.phase_changed(Phase::Finalizing);
+    output.sync()
 }
 
-/// print to stdout progress bar (option)
-fn print_progress_bar(now: &Instant, step: u64, task_num: usize) {
-    let passed = now.elapsed().as_secs();
-    let total = if 0 < step {
-        task_num as u64 * passed / step
-    } else {
-        task_num as u64 * passed
+/// unoptimized inputs of nonce ranges joined to one optimized range
+struct Transpose<'a> {
+    inputs: Vec<(Range<usize>, &'a dyn PlotStorage)>,
+    range: Range<usize>,
+    params: PocParams,
+}
+
+impl Transpose<'_> {
+    /// write scope bands from first_scope, finished bands are recorded if journal
+    ///
+    /// checksums of each band are written before recorded, by `checksum_nonces`
+    fn run(
+        &self,
+        writer: &mut dyn PlotStorage,
+        first_scope: usize,
+        mut journal: Option<&mut File>,
+        config: &ConverterConfig,
+        tracker: &mut ProgressTracker,
+        cancel: &CancelToken,
+    ) -> Result<(), PocError> {
+        let (start, end) = (self.range.start, self.range.end);
+        let params = &self.params;
+
+        // tile is [scope][nonce] ordered like output
+        let nonces = end - start;
+        let scope_count = params.scope_count();
+        let tile_scopes = min(config.tile_scopes(), scope_count);
+        let tile_nonces = min(config.tile_nonces(), nonces);
+        let mut tile = vec![0u8; tile_scopes * tile_nonces * 32];
+        let mut band = vec![0u8; tile_scopes * 32];
+        let checksums = Checksums::with(params, nonces, config.checksum_nonces);
+        if let Some(table) = &checksums {
+            table.write_header(writer)?;
+        }
+
+        // read and join
+        for first_scope in (first_scope..scope_count).step_by(tile_scopes) {
+            let scopes = min(tile_scopes, scope_count - first_scope);
+            let mut sums = checksums.map(|table| BandChecksums::new(table, first_scope, scopes));
+            for first_nonce in (start..end).step_by(tile_nonces) {
+                if cancel.is_cancelled() {
+                    // leave finished bands for resume
+                    return Err(PocError::Cancelled);
+                }
+                let count = min(tile_nonces, end - first_nonce);
+
+                // read scopes of each nonce and transpose
+                for index in 0..count {
+                    let nonce = first_nonce + index;
+                    let (input, fs) = self
+                        .inputs
+                        .iter()
+                        .find(|(input, _)| input.contains(&nonce))
+                        .unwrap();
+                    let pos = scope_offset(
+                        &PlotFlag::Unoptimized,
+                        params,
+                        input.len(),
+                        nonce - input.start,
+                        first_scope,
+                    );
+                    fs.read_at(pos as u64, &mut band[..scopes * 32])
+                        .map_err(|err| {
+                            PocError::Io(format!(
+                                "error on reading nonce {} of {}-{}: {}",
+                                nonce, input.start, input.end, err
+                            ))
+                        })?;
+                    for scope in 0..scopes {
+                        let dst = (scope * count + index) * 32;
+                        tile[dst..dst + 32].copy_from_slice(&band[scope * 32..scope * 32 + 32]);
+                    }
+                }
+
+                // write each scope of the tile
+                for scope in 0..scopes {
+                    let index = first_nonce - start;
+                    let pos = scope_offset(
+                        &PlotFlag::Optimized,
+                        params,
+                        nonces,
+                        index,
+                        first_scope + scope,
+                    );
+                    let row = &tile[scope * count * 32..(scope + 1) * count * 32];
+                    writer.write_at(pos as u64, row)?;
+                    if let Some(sums) = &mut sums {
+                        sums.feed(scope, index, row);
+                    }
+                }
+
+                // show progress
+                tracker.chunk_done(count, (count * scopes * 32) as u64);
+            }
+
+            // record after data and checksums reached the disk
+            if let Some(sums) = &sums {
+                sums.write(writer)?;
+            }
+            if let Some(journal) = &mut journal {
+                writer.sync()?;
+                writeln!(journal, "{}-{}", first_scope, first_scope + scopes)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// chunks written at once by a plotting writer thread
+#[derive(Default)]
+struct StageReport {
+    chunks: usize,
+    nonces: usize,
+    bytes: u64,
+    /// sum of hashing time of the chunks by workers
+    hashing: Duration,
+    writing: Duration,
+}
+
+impl StageReport {
+    fn add(&mut self, other: &StageReport) {
+        self.chunks += other.chunks;
+        self.nonces += other.nonces;
+        self.bytes += other.bytes;
+        self.hashing += other.hashing;
+        self.writing += other.writing;
+    }
+
+    /// rates while each stage is busy, workers hash and writers write in parallel
+    fn rates(&self, workers: usize, writers: usize) -> StageRates {
+        let per_sec = |secs: f64| {
+            if 0.0 < secs {
+                self.bytes as f64 / secs
+            } else {
+                0.0
+            }
+        };
+        StageRates {
+            hashing: per_sec(self.hashing.as_secs_f64() / workers as f64),
+            writing: per_sec(self.writing.as_secs_f64() / writers as f64),
+        }
+    }
+}
+
+/// plot file name without extension
+pub(crate) fn plot_stem(
+    flag: &PlotFlag,
+    addr: &[u8],
+    start: usize,
+    end: usize,
+    version: PocVersion,
+) -> String {
+    let flag = match flag {
+        PlotFlag::Unoptimized => "unoptimized",
+        PlotFlag::Optimized => "optimized",
     };
-    let remain = total.checked_sub(passed).unwrap_or(0);
-    print!(
-        " {} of {} finish, {}m passed, {}m remains  \r",
-        step,
-        task_num,
-        passed / 60,
-        remain / 60
-    );
-    stdout().flush().unwrap();
+    format!(
+        "{}.{}-{}-{}{}",
+        flag,
+        hex::encode(addr),
+        start,
+        end,
+        version.suffix()
+    )
+}
+
+/// scopes finished continuously from zero
+fn finished_scopes(finished: &[(usize, usize)], scope_count: usize) -> usize {
+    let mut done = 0;
+    while let Some((_, band_end)) = finished.iter().find(|(s, e)| *s <= done && done < *e) {
+        done = *band_end;
+    }
+    min(done, scope_count)
 }
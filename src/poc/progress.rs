@@ -0,0 +1,167 @@
+#[cfg(feature = "progress-bar")]
+use std::io::{stdout, Write};
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+/// kind of work a job is doing
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phase {
+    Plotting,
+    Converting,
+    Seeking,
+    /// verify checksums of whole plot
+    Scrubbing,
+    /// flush and rename output file
+    Finalizing,
+}
+
+/// snapshot passed to ProgressSink when a chunk finished
+#[derive(Clone, Debug, PartialEq)]
+pub struct Progress {
+    pub phase: Phase,
+    /// nonces touched by the chunk
+    pub chunk_nonces: usize,
+    /// bytes processed by the chunk
+    pub chunk_bytes: u64,
+    /// bytes processed from job start
+    pub done_bytes: u64,
+    /// bytes of the whole job
+    pub total_bytes: u64,
+    pub elapsed: Duration,
+    /// estimated remaining time, none until some bytes finished
+    pub eta: Option<Duration>,
+}
+
+/// throughput of plotting stages, the lower one is the bottleneck
+#[derive(Clone, Debug, PartialEq)]
+pub struct StageRates {
+    /// bytes per second hashing workers produce while not waiting for buffers
+    pub hashing: f64,
+    /// bytes per second the writer thread writes to disk
+    pub writing: f64,
+}
+
+/// receive progress events of plotting, converting and seeking
+///
+/// all methods do nothing by default
+pub trait ProgressSink: Sync {
+    /// job started with nonce range size and total bytes
+    fn started(&self, _phase: Phase, _nonces: usize, _total_bytes: u64) {}
+
+    /// a chunk of job finished
+    fn chunk_done(&self, _progress: &Progress) {}
+
+    /// rates of hashing and writing stages so far, after chunks written
+    fn stage_rates(&self, _rates: &StageRates) {}
+
+    /// job moved to next phase
+    fn phase_changed(&self, _phase: Phase) {}
+
+    /// nonce ranges failed checksums and skipped by seeking, replot them
+    fn corrupted(&self, _ranges: &[Range<usize>]) {}
+
+    /// job finished (or failed) after elapsed time
+    fn finished(&self, _elapsed: Duration) {}
+}
+
+/// ignore all progress events
+pub struct NoProgress;
+
+impl ProgressSink for NoProgress {}
+
+/// print carriage-return progress bar to stdout
+#[cfg(feature = "progress-bar")]
+pub struct TerminalProgress;
+
+#[cfg(feature = "progress-bar")]
+impl ProgressSink for TerminalProgress {
+    fn chunk_done(&self, progress: &Progress) {
+        let passed = progress.elapsed.as_secs();
+        let remain = progress.eta.map(|eta| eta.as_secs()).unwrap_or(0);
+        let percent = if 0 < progress.total_bytes {
+            progress.done_bytes as f64 * 100.0 / progress.total_bytes as f64
+        } else {
+            100.0
+        };
+        print!(
+            " {:?} {:.1}% finish, {}m passed, {}m remains  \r",
+            progress.phase,
+            percent,
+            passed / 60,
+            remain / 60
+        );
+        stdout().flush().unwrap();
+    }
+
+    fn corrupted(&self, ranges: &[Range<usize>]) {
+        println!("\n skip corrupted nonces {:?}", ranges);
+    }
+
+    fn finished(&self, elapsed: Duration) {
+        println!("\n finished in {}m", elapsed.as_secs() / 60);
+    }
+}
+
+/// count bytes and calculate ETA for a sink, notify finish when dropped
+pub(crate) struct ProgressTracker<'a> {
+    sink: &'a dyn ProgressSink,
+    phase: Phase,
+    now: Instant,
+    done_bytes: u64,
+    total_bytes: u64,
+}
+
+impl<'a> ProgressTracker<'a> {
+    pub fn start(
+        sink: &'a dyn ProgressSink,
+        phase: Phase,
+        nonces: usize,
+        total_bytes: u64,
+    ) -> Self {
+        sink.started(phase, nonces, total_bytes);
+        ProgressTracker {
+            sink,
+            phase,
+            now: Instant::now(),
+            done_bytes: 0,
+            total_bytes,
+        }
+    }
+
+    pub fn chunk_done(&mut self, chunk_nonces: usize, chunk_bytes: u64) {
+        self.done_bytes += chunk_bytes;
+        let elapsed = self.now.elapsed();
+        let eta = if 0 < self.done_bytes {
+            let remain = self.total_bytes.saturating_sub(self.done_bytes);
+            let nanos = elapsed.as_nanos() * remain as u128 / self.done_bytes as u128;
+            Some(Duration::from_nanos(nanos as u64))
+        } else {
+            None
+        };
+        self.sink.chunk_done(&Progress {
+            phase: self.phase,
+            chunk_nonces,
+            chunk_bytes,
+            done_bytes: self.done_bytes,
+            total_bytes: self.total_bytes,
+            elapsed,
+            eta,
+        });
+    }
+
+    pub fn stage_rates(&self, rates: &StageRates) {
+        self.sink.stage_rates(rates);
+    }
+
+    pub fn phase_changed(&mut self, phase: Phase) {
+        self.phase = phase;
+        self.sink.phase_changed(phase);
+    }
+}
+
+impl Drop for ProgressTracker<'_> {
+    /// notify finish on any return
+    fn drop(&mut self) {
+        self.sink.finished(self.now.elapsed());
+    }
+}
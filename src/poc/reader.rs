@@ -0,0 +1,468 @@
+use crate::cancel::CancelToken;
+use crate::capacity::plot_bytes;
+use crate::checksum::{
+    checksum, corrupted_blocks, nonce_checksums, Checksums, CHECKSUM_LEN, HEADER_LEN,
+};
+use crate::error::PocError;
+use crate::lock::open_shared;
+use crate::plotfile::{PlotFile, PlotFlag};
+use crate::storage::{FileStorage, PlotStorage};
+use crate::verifier::PocVerifier;
+use crate::{PocParams, PocVersion};
+use memmap2::Mmap;
+use std::borrow::Cow;
+use std::cmp::{max, min};
+use std::fs::File;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// upper size of a joined read, 16 mainnet nonces
+const COALESCE_SIZE: usize = 8 * 1024 * 1024;
+
+/// strided pieces read at once, joined while the gap between pieces is not
+/// longer than the piece, or than `max_gap` set by `PlotReader::with_coalesce_gap`
+pub(crate) fn coalesced_pieces(stride: usize, len: usize, max_gap: Option<usize>) -> usize {
+    if stride.saturating_sub(len) <= max_gap.unwrap_or(len) {
+        max(1, COALESCE_SIZE / stride)
+    } else {
+        1
+    }
+}
+
+/// byte position of the scope of the nonce index (from start of the file)
+///
+/// - optimized: `[scope0: nonce0, nonce1,..]-[scope1: ..]-..`
+/// - unoptimized: `[nonce0: scope0, scope1,..]-[nonce1: ..]-..`
+pub fn scope_offset(
+    flag: &PlotFlag,
+    params: &PocParams,
+    nonces: usize,
+    index: usize,
+    scope: usize,
+) -> usize {
+    match flag {
+        PlotFlag::Optimized => (scope * nonces + index) * 32,
+        PlotFlag::Unoptimized => index * params.nonce_bytes() + scope * 32,
+    }
+}
+
+enum Source {
+    /// whole file mapped, slices are zero copy. file keeps the shared lock
+    Mapped(Mmap, #[allow(dead_code)] File),
+    /// fallback when mapping failed, or plot not in a file, read to buffer
+    Stored(Box<dyn PlotStorage>),
+}
+
+/// read-only view of a plot file
+///
+/// shared lock is held while opened, converters and other writers of the
+/// file fail instead of changing it under the reader
+pub struct PlotReader {
+    flag: PlotFlag,
+    version: PocVersion,
+    params: PocParams,
+    start: usize,
+    end: usize,
+    source: Source,
+    /// checksum trailer header read once, reset by `with_params`
+    checksums: OnceLock<Option<Checksums>>,
+    /// join strided pieces of the gap, none for gaps not longer than a piece
+    coalesce_gap: Option<usize>,
+}
+
+impl PlotReader {
+    /// map the file, or buffered read if mapping failed
+    ///
+    /// version of the file name suffix, mainnet until `with_params`
+    pub fn open(path: &Path, flag: PlotFlag, start: usize, end: usize) -> Result<Self, PocError> {
+        let version = name_version(path)?;
+        let fs = open_shared(path)?;
+        // safety: plot files are written only before renamed to .dat
+        let source = match unsafe { Mmap::map(&fs) } {
+            Ok(map) => Source::Mapped(map, fs),
+            Err(_) => Source::Stored(Box::new(FileStorage::new(fs))),
+        };
+        Ok(PlotReader {
+            flag,
+            version,
+            params: PocParams::MAINNET,
+            start,
+            end,
+            source,
+            checksums: OnceLock::new(),
+            coalesce_gap: None,
+        })
+    }
+
+    /// always read by buffer, for file systems mapping is slow
+    pub fn open_buffered(
+        path: &Path,
+        flag: PlotFlag,
+        start: usize,
+        end: usize,
+    ) -> Result<Self, PocError> {
+        let version = name_version(path)?;
+        let fs = open_shared(path)?;
+        let reader = PlotReader::from_storage(Box::new(FileStorage::new(fs)), flag, start, end);
+        Ok(reader.with_version(version))
+    }
+
+    /// read the plot from a storage, ex. `MemoryStorage` or `DeviceStorage`
+    ///
+    /// mainnet v1 until `with_version` and `with_params`
+    pub fn from_storage(
+        storage: Box<dyn PlotStorage>,
+        flag: PlotFlag,
+        start: usize,
+        end: usize,
+    ) -> Self {
+        PlotReader {
+            flag,
+            version: PocVersion::V1,
+            params: PocParams::MAINNET,
+            start,
+            end,
+            source: Source::Stored(storage),
+            checksums: OnceLock::new(),
+            coalesce_gap: None,
+        }
+    }
+
+    pub fn from_plot(plot: &PlotFile) -> Result<Self, PocError> {
+        let reader = PlotReader::open(&plot.path, plot.flag.clone(), plot.start, plot.end)?;
+        if reader.version != plot.version {
+            return Err(PocError::Invalid(format!(
+                "{:?} is named as {:?}",
+                plot, reader.version
+            )));
+        }
+        Ok(reader.with_params(plot.params))
+    }
+
+    /// poc version of the plotted hashes
+    pub fn with_version(mut self, version: PocVersion) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// poc geometry of the plotted hashes
+    pub fn with_params(mut self, params: PocParams) -> Self {
+        self.params = params;
+        self.checksums = OnceLock::new();
+        self
+    }
+
+    /// join strided pieces closer than the gap to one read, ex. 1MiB for HDDs
+    /// reading through faster than they seek
+    ///
+    /// unoptimized scopes of mainnet are read by windows of 16 nonces then
+    pub fn with_coalesce_gap(mut self, gap: usize) -> Self {
+        self.coalesce_gap = Some(gap);
+        self
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        matches!(self.source, Source::Mapped(..))
+    }
+
+    pub fn flag(&self) -> &PlotFlag {
+        &self.flag
+    }
+
+    pub fn version(&self) -> PocVersion {
+        self.version
+    }
+
+    pub fn params(&self) -> &PocParams {
+        &self.params
+    }
+
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    pub fn nonces(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// scope hashes of all nonces ordered by nonce, 32 bytes each
+    ///
+    /// zero copy only for mapped optimized file
+    pub fn scope_slice(&self, scope: usize) -> Result<Cow<'_, [u8]>, PocError> {
+        self.scope_slice_inner(scope, None)
+    }
+
+    /// `scope_slice` checking the token between reads of unoptimized file,
+    /// `PocError::Cancelled` to abort a stale seek immediately
+    pub fn scope_slice_cancellable(
+        &self,
+        scope: usize,
+        cancel: &CancelToken,
+    ) -> Result<Cow<'_, [u8]>, PocError> {
+        self.scope_slice_inner(scope, Some(cancel))
+    }
+
+    fn scope_slice_inner(
+        &self,
+        scope: usize,
+        cancel: Option<&CancelToken>,
+    ) -> Result<Cow<'_, [u8]>, PocError> {
+        self.check_scope(scope)?;
+        let first = self.offset(0, scope);
+        match self.flag {
+            PlotFlag::Optimized => {
+                let row = 32 * self.nonces();
+                self.read_strided(first, row, 1, row)
+            }
+            PlotFlag::Unoptimized => {
+                let stride = self.params.nonce_bytes();
+                self.read_windows(first, stride, self.nonces(), 32, cancel)
+            }
+        }
+    }
+
+    /// 32 bytes scope hash of the nonce, same as `PocVerifier::scope_hash`
+    pub fn read_scope(&self, nonce: usize, scope: usize) -> Result<[u8; 32], PocError> {
+        self.check_nonce(nonce)?;
+        self.check_scope(scope)?;
+        let first = self.offset(nonce - self.start, scope);
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(&self.read_strided(first, 32, 1, 32)?);
+        Ok(hash)
+    }
+
+    /// all scope hashes of the nonce, same as `poc_generator` output
+    ///
+    /// zero copy only for mapped unoptimized file
+    pub fn read_nonce(&self, nonce: usize) -> Result<Cow<'_, [u8]>, PocError> {
+        self.check_nonce(nonce)?;
+        let first = self.offset(nonce - self.start, 0);
+        match self.flag {
+            PlotFlag::Optimized => {
+                self.read_strided(first, 32 * self.nonces(), self.params.scope_count(), 32)
+            }
+            PlotFlag::Unoptimized => {
+                let bytes = self.params.nonce_bytes();
+                self.read_strided(first, bytes, 1, bytes)
+            }
+        }
+    }
+
+    /// checksum trailer after the plot data, none if the plot has no checksums
+    pub fn checksums(&self) -> Result<Option<Checksums>, PocError> {
+        if let Some(table) = self.checksums.get() {
+            return Ok(*table);
+        }
+        let table = self.read_checksums()?;
+        Ok(*self.checksums.get_or_init(|| table))
+    }
+
+    fn read_checksums(&self) -> Result<Option<Checksums>, PocError> {
+        let offset = plot_bytes(&self.params, self.nonces());
+        let size = match &self.source {
+            Source::Mapped(map, _) => map.len() as u64,
+            Source::Stored(storage) => storage.size()?,
+        };
+        if size <= offset {
+            return Ok(None);
+        }
+        let header = self.read_strided(offset as usize, HEADER_LEN, 1, HEADER_LEN)?;
+        Checksums::from_header(&self.params, self.nonces(), size, &header).map(Some)
+    }
+
+    /// corrupted nonce ranges of the scope hashes by `scope_slice`
+    ///
+    /// empty if all blocks match or the plot has no checksums
+    pub fn verify_scope(&self, scope: usize, hashes: &[u8]) -> Result<Vec<Range<usize>>, PocError> {
+        let table = match self.checksums()? {
+            Some(table) => table,
+            None => return Ok(vec![]),
+        };
+        assert_eq!(hashes.len(), 32 * self.nonces());
+        let first = table.position(0, scope) as usize;
+        let len = table.blocks() * CHECKSUM_LEN;
+        let sums = self.read_strided(first, len, 1, len)?;
+        let corrupted = corrupted_blocks(&table, &sums, hashes)
+            .into_iter()
+            .map(|range| self.start + range.start..self.start + range.end)
+            .collect();
+        Ok(corrupted)
+    }
+
+    /// first nonce is hashed by the version and params of the reader for the address
+    ///
+    /// `PocError::Invalid` if the file is plotted by other version or address,
+    /// ex. v2 plot renamed without suffix. costs one hash chain
+    pub fn check_version(&self, addr: &[u8]) -> Result<(), PocError> {
+        let mut verifier = PocVerifier::with_params(self.params, self.version);
+        let expect = verifier.scope_hash(addr, self.start as u32, 0);
+        if self.read_scope(self.start, 0)? == expect {
+            return Ok(());
+        }
+        Err(PocError::Invalid(format!(
+            "nonce {} is not {:?} hash of address {}",
+            self.start,
+            self.version,
+            hex::encode(addr)
+        )))
+    }
+
+    /// all scopes of the block match the checksums, read by one span for unoptimized
+    pub fn verify_block(&self, block: usize) -> Result<bool, PocError> {
+        let table = match self.checksums()? {
+            Some(table) => table,
+            None => return Ok(true),
+        };
+        let range = table.block_range(block);
+        let len = table.scope_count * CHECKSUM_LEN;
+        let first = table.position(block, 0) as usize;
+        let stride = table.blocks() * CHECKSUM_LEN;
+        let sums = self.read_strided(first, stride, table.scope_count, CHECKSUM_LEN)?;
+        let expect = match self.flag {
+            PlotFlag::Optimized => {
+                let mut expect = Vec::with_capacity(len);
+                for scope in 0..table.scope_count {
+                    let first = self.offset(range.start, scope);
+                    let hashes = self.read_strided(first, 32, range.len(), 32)?;
+                    expect.extend_from_slice(&checksum(&hashes));
+                }
+                expect
+            }
+            PlotFlag::Unoptimized => {
+                let first = self.offset(range.start, 0);
+                let bytes = self.params.nonce_bytes() * range.len();
+                let data = self.read_strided(first, bytes, 1, bytes)?;
+                nonce_checksums(&self.params, &data, table.block_nonces)
+            }
+        };
+        Ok(sums.as_ref() == &expect[..])
+    }
+
+    fn offset(&self, index: usize, scope: usize) -> usize {
+        scope_offset(&self.flag, &self.params, self.nonces(), index, scope)
+    }
+
+    fn check_nonce(&self, nonce: usize) -> Result<(), PocError> {
+        if nonce < self.start || self.end <= nonce {
+            return Err(PocError::Invalid(format!(
+                "nonce {} out of {}-{}",
+                nonce, self.start, self.end
+            )));
+        }
+        Ok(())
+    }
+
+    fn check_scope(&self, scope: usize) -> Result<(), PocError> {
+        if self.params.scope_count() <= scope {
+            return Err(PocError::Invalid(format!("scope {} out of range", scope)));
+        }
+        Ok(())
+    }
+
+    /// read `count` pieces of `len` bytes every `stride` bytes from `first`
+    fn read_strided(
+        &self,
+        first: usize,
+        stride: usize,
+        count: usize,
+        len: usize,
+    ) -> Result<Cow<'_, [u8]>, PocError> {
+        self.read_windows(first, stride, count, len, None)
+    }
+
+    /// `count` pieces of `len` bytes each `stride`, the token is checked each window
+    fn read_windows(
+        &self,
+        first: usize,
+        stride: usize,
+        count: usize,
+        len: usize,
+        cancel: Option<&CancelToken>,
+    ) -> Result<Cow<'_, [u8]>, PocError> {
+        let is_cancelled = || cancel.is_some_and(|cancel| cancel.is_cancelled());
+        let last = first + stride * (count - 1) + len;
+        match &self.source {
+            Source::Mapped(map, _) => {
+                if map.len() < last {
+                    return Err(PocError::Io(format!(
+                        "plot file too short {}bytes for {}bytes",
+                        map.len(),
+                        last
+                    )));
+                }
+                if count == 1 {
+                    return Ok(Cow::Borrowed(&map[first..last]));
+                }
+                // page in a window at once, not a fault per piece
+                let per_read = coalesced_pieces(stride, len, self.coalesce_gap);
+                let mut buffer = Vec::with_capacity(count * len);
+                for first_index in (0..count).step_by(per_read) {
+                    if is_cancelled() {
+                        return Err(PocError::Cancelled);
+                    }
+                    let pieces = min(per_read, count - first_index);
+                    let pos = first + first_index * stride;
+                    if 1 < pieces {
+                        advise_window(map, pos, stride * (pieces - 1) + len);
+                    }
+                    for index in first_index..first_index + pieces {
+                        let pos = first + index * stride;
+                        buffer.extend_from_slice(&map[pos..pos + len]);
+                    }
+                }
+                Ok(Cow::Owned(buffer))
+            }
+            Source::Stored(storage) => {
+                let mut buffer = vec![0u8; count * len];
+                if count == 1 {
+                    storage.read_at(first as u64, &mut buffer)?;
+                    return Ok(Cow::Owned(buffer));
+                }
+                // join near pieces to one read
+                let per_read = coalesced_pieces(stride, len, self.coalesce_gap);
+                let mut span = vec![0u8; stride * (per_read - 1) + len];
+                for first_index in (0..count).step_by(per_read) {
+                    if is_cancelled() {
+                        return Err(PocError::Cancelled);
+                    }
+                    let pieces = min(per_read, count - first_index);
+                    let span_len = stride * (pieces - 1) + len;
+                    let pos = (first + first_index * stride) as u64;
+                    storage.read_at(pos, &mut span[..span_len])?;
+                    for index in 0..pieces {
+                        let dst = (first_index + index) * len;
+                        buffer[dst..dst + len]
+                            .copy_from_slice(&span[index * stride..index * stride + len]);
+                    }
+                }
+                Ok(Cow::Owned(buffer))
+            }
+        }
+    }
+}
+
+/// version of the plot file name suffix, v1 if not named like a plot
+fn name_version(path: &Path) -> Result<PocVersion, PocError> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let stem = name.strip_suffix(".dat").unwrap_or(&name);
+    let suffix = match stem.rfind('.') {
+        Some(pos) if stem[pos + 1..].starts_with('v') => &stem[pos..],
+        _ => "",
+    };
+    PocVersion::from_suffix(suffix)
+        .ok_or_else(|| PocError::Invalid(format!("unknown version {} of {:?}", suffix, path)))
+}
+
+/// read the window of the map ahead, best effort
+#[cfg(unix)]
+fn advise_window(map: &Mmap, pos: usize, len: usize) {
+    map.advise_range(memmap2::Advice::WillNeed, pos, len).ok();
+}
+
+#[cfg(not(unix))]
+fn advise_window(_map: &Mmap, _pos: usize, _len: usize) {}
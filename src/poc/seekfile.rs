@@ -1,16 +1,127 @@
-use crate::get_scope_index;
-use blake2b_simd::{blake2b, Hash};
-use std::cmp::min;
-use std::fs::File;
-use std::io::{BufReader, Read, Seek, SeekFrom};
+use crate::cancel::CancelToken;
+use crate::error::PocError;
+use crate::plotfile::{PlotFile, PlotFlag};
+use crate::progress::{Phase, ProgressSink, ProgressTracker};
+use crate::reader::PlotReader;
+use crate::PocVersion;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::ops::Range;
 use std::path::Path;
-use std::sync::mpsc;
 use std::thread;
-use std::time::Instant;
 
-/// seek a optimized plot file
+/// nonces reported as one chunk by single thread seeking
+const PROGRESS_CHUNK: usize = 4096;
+
+/// which works seeking returns
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SeekMode {
+    /// first found work, stop seeking soon
+    First,
+    /// the lowest work in range
+    Best,
+    /// the k lowest works in range
+    TopK(usize),
+    /// all works lower than target
+    All,
+}
+
+/// a work ordered by work hash, then by nonce
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Hit {
+    nonce: u32,
+    work: Vec<u8>,
+}
+
+impl Ord for Hit {
+    fn cmp(&self, other: &Self) -> Ordering {
+        work_cmp(&self.work, &other.work).then(self.nonce.cmp(&other.nonce))
+    }
+}
+
+impl PartialOrd for Hit {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// works found by seeking, sorted from the lowest work when taken
+///
+/// bounded modes keep a max-heap of the lowest works, all mode appends
+#[derive(Clone, Debug)]
+pub(crate) struct Hits {
+    mode: SeekMode,
+    works: BinaryHeap<Hit>,
+    all: Vec<Hit>,
+}
+
+impl Hits {
+    pub fn new(mode: SeekMode) -> Self {
+        Hits {
+            mode,
+            works: BinaryHeap::new(),
+            all: vec![],
+        }
+    }
+
+    /// add a work already checked lower than target
+    pub fn push(&mut self, nonce: u32, work: &[u8]) {
+        let limit = match self.mode {
+            SeekMode::First | SeekMode::Best => 1,
+            SeekMode::TopK(k) => k,
+            SeekMode::All => {
+                self.all.push(Hit {
+                    nonce,
+                    work: work.to_vec(),
+                });
+                return;
+            }
+        };
+        if self.works.len() < limit {
+            self.works.push(Hit {
+                nonce,
+                work: work.to_vec(),
+            });
+        } else if let Some(mut highest) = self.works.peek_mut() {
+            // replace the highest kept work
+            if work_cmp(work, &highest.work) == Ordering::Less {
+                highest.nonce = nonce;
+                highest.work = work.to_vec();
+            }
+        }
+    }
+
+    /// seeking can stop by the mode
+    pub fn is_enough(&self) -> bool {
+        self.mode == SeekMode::First && !self.works.is_empty()
+    }
+
+    pub fn merge(&mut self, other: Hits) {
+        if self.is_enough() {
+            return;
+        }
+        self.all.extend(other.all);
+        for hit in other.works {
+            self.push(hit.nonce, &hit.work);
+        }
+    }
+
+    /// error when no work found
+    pub fn into_works(self) -> Result<Vec<(u32, Vec<u8>)>, PocError> {
+        let mut works = self.all;
+        works.extend(self.works);
+        if works.is_empty() {
+            return Err(PocError::NotFound);
+        }
+        works.sort_unstable();
+        Ok(works.into_iter().map(|hit| (hit.nonce, hit.work)).collect())
+    }
+}
+
+/// seek a v1 optimized plot file
 ///
-/// return (nonce, workHash)
+/// return (nonce, workHash), stop with `PocError::Cancelled` when cancel requested
+#[allow(clippy::too_many_arguments)]
 pub fn seek_file(
     path: &Path,
     start: usize,
@@ -19,108 +130,370 @@ pub fn seek_file(
     target: &[u8],
     time: u32,
     multi: bool,
-) -> Result<(u32, Vec<u8>), String> {
+    progress: &dyn ProgressSink,
+    cancel: &CancelToken,
+) -> Result<(u32, Vec<u8>), PocError> {
+    let works = seek_file_by_mode(
+        path,
+        start,
+        end,
+        previous_hash,
+        target,
+        time,
+        SeekMode::First,
+        multi,
+        progress,
+        cancel,
+    )?;
+    Ok(works.into_iter().next().unwrap())
+}
+
+/// seek a v1 optimized plot file and collect works lower than target by the mode
+///
+/// return [(nonce, workHash),..] ordered from the lowest work, never empty,
+/// `PocError::Invalid` for plots named with other versions, use `seek_plot` for them
+#[allow(clippy::too_many_arguments)]
+pub fn seek_file_by_mode(
+    path: &Path,
+    start: usize,
+    end: usize,
+    previous_hash: &[u8],
+    target: &[u8],
+    time: u32,
+    mode: SeekMode,
+    multi: bool,
+    progress: &dyn ProgressSink,
+    cancel: &CancelToken,
+) -> Result<Vec<(u32, Vec<u8>)>, PocError> {
     assert!(start < end);
+    let reader = PlotReader::open(path, PlotFlag::Optimized, start, end)?;
+    if reader.version() != PocVersion::V1 {
+        return Err(PocError::Invalid(format!(
+            "{:?} is {:?} plot, seek by seek_plot",
+            path,
+            reader.version()
+        )));
+    }
+    seek_reader(
+        &reader,
+        previous_hash,
+        target,
+        time,
+        mode,
+        multi,
+        progress,
+        cancel,
+    )
+}
+
+/// seek a plot file of either layout and collect works lower than target by the mode
+///
+/// unoptimized file is slow (a small read per nonce) but can be mined before converted,
+/// return [(nonce, workHash),..] ordered from the lowest work, never empty
+#[allow(clippy::too_many_arguments)]
+pub fn seek_plot(
+    plot: &PlotFile,
+    previous_hash: &[u8],
+    target: &[u8],
+    time: u32,
+    mode: SeekMode,
+    multi: bool,
+    progress: &dyn ProgressSink,
+    cancel: &CancelToken,
+) -> Result<Vec<(u32, Vec<u8>)>, PocError> {
+    assert!(plot.start < plot.end);
+    let reader = PlotReader::from_plot(plot)?;
+    seek_reader(
+        &reader,
+        previous_hash,
+        target,
+        time,
+        mode,
+        multi,
+        progress,
+        cancel,
+    )
+}
+
+/// seek scope of the reader by the mode, ex. a plot in `MemoryStorage`
+///
+/// return [(nonce, workHash),..] ordered from the lowest work, never empty
+#[allow(clippy::too_many_arguments)]
+pub fn seek_reader(
+    reader: &PlotReader,
+    previous_hash: &[u8],
+    target: &[u8],
+    time: u32,
+    mode: SeekMode,
+    multi: bool,
+    progress: &dyn ProgressSink,
+    cancel: &CancelToken,
+) -> Result<Vec<(u32, Vec<u8>)>, PocError> {
     assert_eq!(previous_hash.len(), 32);
     assert_eq!(target.len(), 32);
-    let now = Instant::now();
+    assert_ne!(mode, SeekMode::TopK(0));
+    let (start, end) = (reader.start(), reader.end());
+    let version = reader.version();
+    let total_bytes = (32 * (end - start)) as u64;
+    let mut tracker = ProgressTracker::start(progress, Phase::Seeking, end - start, total_bytes);
 
-    // get file object
-    let raw_fs = File::open(path).map_err(|err| err.to_string())?;
-    let mut fs = BufReader::new(raw_fs);
+    if cancel.is_cancelled() {
+        return Err(PocError::Cancelled);
+    }
 
-    // setup first position
-    let scope_index = get_scope_index(previous_hash);
-    let start_pos = (scope_index * 32 * (end - start)) as u64;
-    fs.seek(SeekFrom::Start(start_pos))
-        .map_err(|err| err.to_string())?;
+    // get scope hashes of all nonces, verified lazily if the plot has checksums
+    let scope_index = reader.params().scope_index(previous_hash);
+    let scope = reader.scope_slice_cancellable(scope_index, cancel)?;
+    let corrupted = verified_scope(reader, scope_index, &scope, progress)?;
+    let corrupted = corrupted.as_slice();
 
     // user select by multi thread or single thread
+    let mut hits = Hits::new(mode);
+    // stop other threads when enough works found
+    let enough = CancelToken::new();
     if multi {
-        // seek by multi thread
-        let cache = prepare_cache(time, &previous_hash);
-        let (tx, rx) = mpsc::channel();
-
+        // seek by multi thread, each scans own slice in place
         // ex. start=0, end=10, cpu_count=3 step_size=4: 0,1,2,3 ,4,5,6,7 ,8,9
         let cpu_count = num_cpus::get();
         let step_size = (end - start) / cpu_count + 1;
-        let mut buffer = vec![0u8; step_size * 32];
-        let mut start_pos = start.clone();
-
-        // throw tasks to all cpus
-        for _ in 0..cpu_count {
-            match fs.read(&mut buffer) {
-                Ok(size) => {
-                    let tx = tx.clone();
-                    let buffer = buffer.clone();
-                    let mut cache = cache.clone();
-                    let target = target.to_vec();
-                    let len = size / 32;
-                    let end_pos = min(start_pos + len, end);
-                    thread::spawn(move || {
-                        for (index, nonce) in (start_pos..end_pos).enumerate() {
-                            let scope_hash = &buffer[index * 32..index * 32 + 32];
-                            let raw_work = poc_hash_from_scope(scope_hash, &mut cache);
-                            let work_ref = &raw_work.as_bytes()[0..32];
-                            if work_check(work_ref, &target) {
-                                tx.send(Some((nonce as u32, work_ref.to_vec()))).unwrap();
-                                return;
-                            }
-                        }
-                        tx.send(None).unwrap();
-                    });
-                    // next step
-                    start_pos += len;
-                }
-                Err(err) => return Err(err.to_string()),
+        let results = thread::scope(|s| {
+            let handles = scope
+                .chunks(step_size * 32)
+                .enumerate()
+                .map(|(step, buffer)| {
+                    let enough = &enough;
+                    s.spawn(move || {
+                        let mut hits = Hits::new(mode);
+                        let first_nonce = start + step * step_size;
+                        let seeked = seek_slice(
+                            version,
+                            buffer,
+                            first_nonce,
+                            corrupted,
+                            time,
+                            previous_hash,
+                            target,
+                            &mut hits,
+                            cancel,
+                            enough,
+                        );
+                        (seeked, hits)
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("seeking thread panicked"))
+                .collect::<Vec<_>>()
+        });
+
+        // wait for all thread finish
+        for (seeked, result) in results {
+            tracker.chunk_done(seeked, (32 * seeked) as u64);
+            hits.merge(result);
+        }
+    } else {
+        // seek by single thread
+        for (step, buffer) in scope.chunks(PROGRESS_CHUNK * 32).enumerate() {
+            let first_nonce = start + step * PROGRESS_CHUNK;
+            let seeked = seek_slice(
+                version,
+                buffer,
+                first_nonce,
+                corrupted,
+                time,
+                previous_hash,
+                target,
+                &mut hits,
+                cancel,
+                &enough,
+            );
+            // report every chunk
+            tracker.chunk_done(seeked, (32 * seeked) as u64);
+            if seeked * 32 < buffer.len() {
+                break;
             }
         }
+    }
 
-        // wait for all thread finish
-        let mut success = None;
-        for result in rx.iter().take(cpu_count) {
-            match result {
-                Some((nonce, work)) => {
-                    if success.is_none() {
-                        success.replace((nonce, work));
-                    }
-                    // ignore other works
-                }
-                None => continue,
+    // note: works found before cancel are useless for caller
+    if !hits.is_enough() && cancel.is_cancelled() {
+        return Err(PocError::Cancelled);
+    }
+    hits.into_works()
+}
+
+/// seek scope hashes of sequential nonces from first_nonce, except corrupted nonces
+///
+/// return seeked nonce number, stop when cancelled or enough works found
+#[allow(clippy::too_many_arguments)]
+fn seek_slice(
+    version: PocVersion,
+    buffer: &[u8],
+    first_nonce: usize,
+    corrupted: &[Range<usize>],
+    time: u32,
+    previous_hash: &[u8],
+    target: &[u8],
+    hits: &mut Hits,
+    cancel: &CancelToken,
+    enough: &CancelToken,
+) -> usize {
+    let mut cache = prepare_cache(time, previous_hash);
+    let mut seeked = 0;
+    for (index, scope_hash) in buffer.chunks_exact(32).enumerate() {
+        if cancel.is_cancelled() || enough.is_cancelled() {
+            break;
+        }
+        seeked += 1;
+        let nonce = first_nonce + index;
+        if is_corrupted(nonce, corrupted) {
+            continue;
+        }
+        let work = poc_hash_from_scope(version, scope_hash, &mut cache);
+        if work_check(&work, target) {
+            hits.push(nonce as u32, &work);
+            if hits.is_enough() {
+                enough.cancel();
+                break;
             }
         }
+    }
+    seeked
+}
 
-        // return generated result
-        success.ok_or(format!(
-            "full seeked but not found enough work {}mSec",
-            now.elapsed().as_millis()
-        ))
-    } else {
-        // seek by single thread
-        let mut buffer = [0u8; 32];
-        let mut cache = prepare_cache(time, previous_hash);
-        for nonce in start..end {
-            match fs.read(&mut buffer) {
-                Ok(32) => {
-                    let raw_work = poc_hash_from_scope(&buffer, &mut cache);
-                    let work_ref = &raw_work.as_bytes()[0..32];
-                    if work_check(work_ref, target) {
-                        return Ok((nonce as u32, work_ref.to_vec()));
+/// seek a plot file for all block times of the window
+///
+/// scope is read once and hashed with each time in memory,
+/// return (time, nonce, workHash) of the earliest time and the lowest work at that time
+pub fn seek_time_window(
+    plot: &PlotFile,
+    previous_hash: &[u8],
+    target: &[u8],
+    times: Range<u32>,
+    progress: &dyn ProgressSink,
+    cancel: &CancelToken,
+) -> Result<(u32, u32, Vec<u8>), PocError> {
+    assert!(plot.start < plot.end);
+    assert!(times.start < times.end);
+    assert_eq!(previous_hash.len(), 32);
+    assert_eq!(target.len(), 32);
+    let (start, end, version) = (plot.start, plot.end, plot.version);
+    let total_bytes = (32 * (end - start)) as u64;
+    let mut tracker = ProgressTracker::start(progress, Phase::Seeking, end - start, total_bytes);
+
+    if cancel.is_cancelled() {
+        return Err(PocError::Cancelled);
+    }
+
+    // read whole scope once
+    let reader = PlotReader::from_plot(plot)?;
+    let scope_index = plot.params.scope_index(previous_hash);
+    let scope = reader.scope_slice_cancellable(scope_index, cancel)?;
+    let corrupted = verified_scope(&reader, scope_index, &scope, progress)?;
+    let corrupted = corrupted.as_slice();
+
+    // each thread finds the earliest time of its nonces
+    let cpu_count = num_cpus::get();
+    let step_size = (end - start) / cpu_count + 1;
+    let results = thread::scope(|s| {
+        let handles = scope
+            .chunks(step_size * 32)
+            .enumerate()
+            .map(|(step, buffer)| {
+                let times = times.clone();
+                s.spawn(move || {
+                    let mut best: Option<(u32, u32, Vec<u8>)> = None;
+                    let mut caches = times
+                        .clone()
+                        .map(|time| prepare_cache(time, previous_hash))
+                        .collect::<Vec<_>>();
+                    for (index, scope_hash) in buffer.chunks(32).enumerate() {
+                        if cancel.is_cancelled() {
+                            break;
+                        }
+                        let nonce = start + step * step_size + index;
+                        if is_corrupted(nonce, corrupted) {
+                            continue;
+                        }
+                        let nonce = nonce as u32;
+                        for (time, cache) in times.clone().zip(caches.iter_mut()) {
+                            if matches!(&best, Some((best_time, _, _)) if *best_time < time) {
+                                // later than found
+                                break;
+                            }
+                            let work = poc_hash_from_scope(version, scope_hash, cache);
+                            if work_check(&work, target) {
+                                if is_earlier(time, &work, &best) {
+                                    best.replace((time, nonce, work.to_vec()));
+                                }
+                                break;
+                            }
+                        }
                     }
-                }
-                Ok(size) => return Err(format!("wrong read size {} bytes", size)),
-                Err(err) => return Err(err.to_string()),
+                    (buffer.len() / 32, best)
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("seeking thread panicked"))
+            .collect::<Vec<_>>()
+    });
+
+    // select the earliest and lowest work
+    let mut best: Option<(u32, u32, Vec<u8>)> = None;
+    for (seeked, result) in results {
+        tracker.chunk_done(seeked, (32 * seeked) as u64);
+        if let Some((time, nonce, work)) = result {
+            if is_earlier(time, &work, &best) {
+                best.replace((time, nonce, work));
             }
         }
-        Err(format!(
-            "full seeked but not found enough work {}mSec",
-            now.elapsed().as_millis()
-        ))
+    }
+    if cancel.is_cancelled() {
+        return Err(PocError::Cancelled);
+    }
+    best.ok_or(PocError::NotFound)
+}
+
+/// nonce ranges of the scope failed checksums, reported to the sink
+///
+/// `PocError::Corrupted` only if no nonce is left to seek
+fn verified_scope(
+    reader: &PlotReader,
+    scope_index: usize,
+    scope: &[u8],
+    progress: &dyn ProgressSink,
+) -> Result<Vec<Range<usize>>, PocError> {
+    let corrupted = reader.verify_scope(scope_index, scope)?;
+    if corrupted.is_empty() {
+        return Ok(corrupted);
+    }
+    progress.corrupted(&corrupted);
+    let lost: usize = corrupted.iter().map(|range| range.len()).sum();
+    if lost == reader.nonces() {
+        return Err(PocError::Corrupted(corrupted));
+    }
+    Ok(corrupted)
+}
+
+fn is_corrupted(nonce: usize, corrupted: &[Range<usize>]) -> bool {
+    corrupted.iter().any(|range| range.contains(&nonce))
+}
+
+/// earlier time, or lower work at same time than best (time, nonce, workHash)
+fn is_earlier(time: u32, work: &[u8], best: &Option<(u32, u32, Vec<u8>)>) -> bool {
+    match best {
+        Some((best_time, _, best_work)) => {
+            time < *best_time || (time == *best_time && work_check(work, best_work))
+        }
+        None => true,
     }
 }
 
 /// prepare cache array for poc_hash_from_scope()
-fn prepare_cache(time: u32, previous_hash: &[u8]) -> [u8; 4 + 32 + 32] {
+pub(crate) fn prepare_cache(time: u32, previous_hash: &[u8]) -> [u8; 4 + 32 + 32] {
     let mut cache = [0u8; 4 + 32 + 32];
     let time: [u8; 4] = time.to_le_bytes();
     cache[0..4].clone_from_slice(&time);
@@ -131,26 +504,26 @@ fn prepare_cache(time: u32, previous_hash: &[u8]) -> [u8; 4 + 32 + 32] {
 
 /// get poc_hash from scope_hash with low-cost
 #[inline]
-fn poc_hash_from_scope(scope_hash: &[u8], cache: &mut [u8; 4 + 32 + 32]) -> Hash {
+pub(crate) fn poc_hash_from_scope(
+    version: PocVersion,
+    scope_hash: &[u8],
+    cache: &mut [u8; 4 + 32 + 32],
+) -> [u8; 32] {
     // time and previous_hash is already written
     // cache[0..4].clone_from_slice(&time);
     cache[4..4 + 32].clone_from_slice(scope_hash);
     // cache[36..36 + 32].clone_from_slice(previous_hash);
-    blake2b(cache.as_ref())
+    version.work_hash(cache.as_ref())
+}
+
+/// compare works as 32 bytes little-endian int
+pub(crate) fn work_cmp(work: &[u8], other: &[u8]) -> Ordering {
+    work.iter().rev().cmp(other.iter().rev())
 }
 
 /// check the work enough lower than target (little-endian)
 #[inline]
-fn work_check(work: &[u8], target: &[u8]) -> bool {
+pub(crate) fn work_check(work: &[u8], target: &[u8]) -> bool {
     // "target > work" => true
-    for (work, target) in work.iter().rev().zip(target.iter().rev()) {
-        if work > target {
-            return false;
-        } else if work < target {
-            return true;
-        } else {
-            continue;
-        }
-    }
-    false
+    work_cmp(work, target) == Ordering::Less
 }
@@ -0,0 +1,284 @@
+use crate::error::PocError;
+use crate::lock::lock_exclusive;
+use fs2::FileExt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// O_DIRECT writes are aligned to logical block size, 4KiB covers common disks
+pub const DIRECT_ALIGN: usize = 4096;
+
+/// positioned bytes a plot is written to and read from
+///
+/// reads are shared by seeking threads, writes are done by one thread
+pub trait PlotStorage: Send + Sync {
+    /// fill the buffer from the position, error if out of the storage
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> Result<(), PocError>;
+
+    /// write all data at the position
+    fn write_at(&mut self, pos: u64, data: &[u8]) -> Result<(), PocError>;
+
+    /// bytes of the storage
+    fn size(&self) -> Result<u64, PocError>;
+
+    /// reserve bytes before writing, `PocError::NoSpace` if it cannot be
+    fn allocate(&mut self, len: u64) -> Result<(), PocError>;
+
+    /// written data reach the device
+    fn sync(&mut self) -> Result<(), PocError>;
+}
+
+/// plot file on a file system
+///
+/// with direct io, aligned middle of a write bypasses page cache and unaligned
+/// head and tail are written through it, so output is same with any options
+pub struct FileStorage {
+    fs: Mutex<File>,
+    direct: Option<File>,
+    bounce: Vec<u8>,
+}
+
+impl FileStorage {
+    pub fn new(fs: File) -> Self {
+        FileStorage {
+            fs: Mutex::new(fs),
+            direct: None,
+            bounce: vec![],
+        }
+    }
+
+    /// write bypassing page cache if the file system allows (linux O_DIRECT)
+    pub fn with_direct_io(mut self, path: &Path) -> Self {
+        self.direct = open_direct(path);
+        self
+    }
+
+    /// output file of plotter and converter, preallocated not to be fragmented
+    pub(crate) fn output(
+        fs: File,
+        path: &Path,
+        len: u64,
+        preallocate: bool,
+        direct_io: bool,
+    ) -> Result<Self, PocError> {
+        let mut storage = FileStorage::new(fs);
+        if direct_io {
+            storage = storage.with_direct_io(path);
+        }
+        if preallocate {
+            storage.allocate(len)?;
+        }
+        Ok(storage)
+    }
+
+    /// cut or extend to the length, ex. exact size of the plot before renamed
+    pub fn set_len(&mut self, len: u64) -> Result<(), PocError> {
+        Ok(self.fs.get_mut().unwrap().set_len(len)?)
+    }
+
+    pub fn into_file(self) -> File {
+        self.fs.into_inner().unwrap()
+    }
+}
+
+impl PlotStorage for FileStorage {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> Result<(), PocError> {
+        read_file(&mut self.fs.lock().unwrap(), pos, buf)
+    }
+
+    fn write_at(&mut self, pos: u64, data: &[u8]) -> Result<(), PocError> {
+        let fs = self.fs.get_mut().unwrap();
+        let direct = match &mut self.direct {
+            Some(direct) => direct,
+            None => return write_file(fs, pos, data),
+        };
+        let align = DIRECT_ALIGN as u64;
+        let first = pos.next_multiple_of(align);
+        let last = (pos + data.len() as u64) / align * align;
+        if last <= first {
+            return write_file(fs, pos, data);
+        }
+        let (head, rest) = data.split_at((first - pos) as usize);
+        let (middle, tail) = rest.split_at((last - first) as usize);
+        write_file(fs, pos, head)?;
+
+        // copy to aligned memory
+        let bounce = &mut self.bounce;
+        if bounce.len() < middle.len() + DIRECT_ALIGN {
+            bounce.resize(middle.len() + DIRECT_ALIGN, 0);
+        }
+        let offset = bounce.as_ptr().align_offset(DIRECT_ALIGN);
+        let aligned = &mut bounce[offset..offset + middle.len()];
+        aligned.copy_from_slice(middle);
+        write_file(direct, first, aligned)?;
+        write_file(fs, last, tail)
+    }
+
+    fn size(&self) -> Result<u64, PocError> {
+        Ok(self.fs.lock().unwrap().metadata()?.len())
+    }
+
+    fn allocate(&mut self, len: u64) -> Result<(), PocError> {
+        // note: posix_fallocate, extents are reserved without writing zeros
+        Ok(FileExt::allocate(self.fs.get_mut().unwrap(), len)?)
+    }
+
+    fn sync(&mut self) -> Result<(), PocError> {
+        Ok(self.fs.get_mut().unwrap().sync_data()?)
+    }
+}
+
+/// plot in memory, ex. tests plotting small ranges without disks
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MemoryStorage {
+    data: Vec<u8>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage::default()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl From<Vec<u8>> for MemoryStorage {
+    fn from(data: Vec<u8>) -> Self {
+        MemoryStorage { data }
+    }
+}
+
+impl PlotStorage for MemoryStorage {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> Result<(), PocError> {
+        let first = pos as usize;
+        match self.data.get(first..first + buf.len()) {
+            Some(data) => {
+                buf.copy_from_slice(data);
+                Ok(())
+            }
+            None => Err(PocError::Io(format!(
+                "read {}bytes at {} out of {}bytes",
+                buf.len(),
+                pos,
+                self.data.len()
+            ))),
+        }
+    }
+
+    fn write_at(&mut self, pos: u64, data: &[u8]) -> Result<(), PocError> {
+        let first = pos as usize;
+        if self.data.len() < first + data.len() {
+            self.data.resize(first + data.len(), 0);
+        }
+        self.data[first..first + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn size(&self) -> Result<u64, PocError> {
+        Ok(self.data.len() as u64)
+    }
+
+    fn allocate(&mut self, len: u64) -> Result<(), PocError> {
+        if (self.data.len() as u64) < len {
+            self.data.resize(len as usize, 0);
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), PocError> {
+        Ok(())
+    }
+}
+
+/// raw block device (ex. /dev/sdb1) holding one plot without file system
+///
+/// locked exclusively while opened, size is fixed by the device
+pub struct DeviceStorage {
+    fs: Mutex<File>,
+    size: u64,
+}
+
+impl DeviceStorage {
+    pub fn open(path: &Path) -> Result<Self, PocError> {
+        let mut fs = OpenOptions::new().read(true).write(true).open(path)?;
+        lock_exclusive(&fs, path)?;
+        // note: metadata of block device has no size
+        let size = fs.seek(SeekFrom::End(0))?;
+        Ok(DeviceStorage {
+            fs: Mutex::new(fs),
+            size,
+        })
+    }
+
+    fn check(&self, pos: u64, len: usize) -> Result<(), PocError> {
+        let needed = pos + len as u64;
+        if self.size < needed {
+            return Err(PocError::NoSpace {
+                needed,
+                available: self.size,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl PlotStorage for DeviceStorage {
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> Result<(), PocError> {
+        self.check(pos, buf.len())?;
+        read_file(&mut self.fs.lock().unwrap(), pos, buf)
+    }
+
+    fn write_at(&mut self, pos: u64, data: &[u8]) -> Result<(), PocError> {
+        self.check(pos, data.len())?;
+        write_file(self.fs.get_mut().unwrap(), pos, data)
+    }
+
+    fn size(&self) -> Result<u64, PocError> {
+        Ok(self.size)
+    }
+
+    fn allocate(&mut self, len: u64) -> Result<(), PocError> {
+        self.check(0, len as usize)
+    }
+
+    fn sync(&mut self) -> Result<(), PocError> {
+        Ok(self.fs.get_mut().unwrap().sync_data()?)
+    }
+}
+
+fn read_file(fs: &mut File, pos: u64, buf: &mut [u8]) -> Result<(), PocError> {
+    fs.seek(SeekFrom::Start(pos))?;
+    fs.read_exact(buf)?;
+    Ok(())
+}
+
+fn write_file(fs: &mut File, pos: u64, data: &[u8]) -> Result<(), PocError> {
+    if !data.is_empty() {
+        fs.seek(SeekFrom::Start(pos))?;
+        fs.write_all(data)?;
+    }
+    Ok(())
+}
+
+/// O_DIRECT handle of the file, none if the file system refuses (ex. tmpfs)
+#[cfg(target_os = "linux")]
+fn open_direct(path: &Path) -> Option<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)
+        .ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_direct(_path: &Path) -> Option<File> {
+    None
+}
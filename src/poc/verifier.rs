@@ -0,0 +1,109 @@
+use crate::error::PocError;
+use crate::seekfile::{poc_hash_from_scope, prepare_cache, work_check};
+use crate::{hash_chain, PocParams, PocVersion};
+
+/// 32 bytes work hash
+pub type Hash = [u8; 32];
+
+/// check works of blocks reusing one generator cache
+///
+/// only the requested scope is taken out of the hash chain, so no 512KiB
+/// output buffer is needed
+pub struct PocVerifier {
+    params: PocParams,
+    version: PocVersion,
+    cache: Box<[u8]>,
+}
+
+impl Default for PocVerifier {
+    fn default() -> Self {
+        PocVerifier::new()
+    }
+}
+
+impl PocVerifier {
+    /// verifier of mainnet v1 works
+    pub fn new() -> Self {
+        PocVerifier::with_version(PocVersion::V1)
+    }
+
+    /// verifier of mainnet works
+    pub fn with_version(version: PocVersion) -> Self {
+        PocVerifier::with_params(PocParams::MAINNET, version)
+    }
+
+    pub fn with_params(params: PocParams, version: PocVersion) -> Self {
+        PocVerifier {
+            params,
+            version,
+            cache: params.generator_cache(),
+        }
+    }
+
+    pub fn params(&self) -> &PocParams {
+        &self.params
+    }
+
+    pub fn version(&self) -> PocVersion {
+        self.version
+    }
+
+    /// switch version, e.g. for blocks after the fork height
+    pub fn set_version(&mut self, version: PocVersion) {
+        self.version = version;
+    }
+
+    /// 32 bytes scope hash of the nonce, same as the scope of `poc_generator` output
+    pub fn scope_hash(&mut self, addr: &[u8], nonce: u32, scope: usize) -> [u8; 32] {
+        assert_eq!(addr.len(), self.params.addr_len());
+        assert!(scope < self.params.scope_count());
+        let final_hash = hash_chain(&self.params, self.version, addr, nonce, &mut self.cache);
+        let (loop_count, hash_len) = (self.params.loop_count(), self.params.hash_len());
+        let mut scope_hash = [0u8; 32];
+        for (index, item) in scope_hash.iter_mut().enumerate() {
+            let pos = scope * 32 + index;
+            let inner_pos = pos % hash_len;
+            let outer_pos = pos / hash_len;
+            *item = final_hash[inner_pos]
+                ^ self.cache[(loop_count - outer_pos - 1) * hash_len + inner_pos];
+        }
+        scope_hash
+    }
+
+    /// work hash of the nonce at the block time
+    pub fn work(&mut self, addr: &[u8], nonce: u32, time: u32, previous_hash: &[u8]) -> Hash {
+        assert_eq!(previous_hash.len(), 32);
+        let scope = self.params.scope_index(previous_hash);
+        let scope_hash = self.scope_hash(addr, nonce, scope);
+        let mut cache = prepare_cache(time, previous_hash);
+        poc_hash_from_scope(self.version, &scope_hash, &mut cache)
+    }
+
+    /// work hash if lower than the target, for block validation
+    pub fn verify(
+        &mut self,
+        addr: &[u8],
+        nonce: u32,
+        time: u32,
+        previous_hash: &[u8],
+        target: &[u8],
+    ) -> Result<Hash, PocError> {
+        if addr.len() != self.params.addr_len() || previous_hash.len() != 32 || target.len() != 32 {
+            return Err(PocError::Invalid(format!(
+                "address, previous hash and target are {}, {} and {}bytes",
+                addr.len(),
+                previous_hash.len(),
+                target.len()
+            )));
+        }
+        let work = self.work(addr, nonce, time, previous_hash);
+        if work_check(&work, target) {
+            Ok(work)
+        } else {
+            Err(PocError::Invalid(format!(
+                "work {} is not lower than target",
+                hex::encode(work)
+            )))
+        }
+    }
+}